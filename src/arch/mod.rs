@@ -9,6 +9,11 @@ pub unsafe fn write_stvec(handler: usize) {
     asm!("csrw stvec, {}", in(reg) handler);
 }
 
+#[inline(always)]
+pub unsafe fn write_sscratch(sscratch: usize) {
+    asm!("csrw sscratch, {}", in(reg) sscratch);
+}
+
 #[inline(always)]
 pub fn read_time() -> usize {
     let time;
@@ -33,3 +38,19 @@ pub fn read_tp() -> usize {
     }
     tp
 }
+
+/// clear pending supervisor software interrupt (IPI)
+#[inline(always)]
+pub fn clear_software_interrupt() {
+    unsafe {
+        asm!("csrci sip, 1<<1");
+    }
+}
+
+/// wait for interrupt, pending interrupt wakes up hart even if `SIE` is off
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("wfi");
+    }
+}
@@ -37,6 +37,14 @@ pub fn print(args: fmt::Arguments) {
     STDOUT.lock().write_fmt(args).unwrap();
 }
 
+/// write raw bytes to stdout
+pub fn write_bytes(bytes: &[u8]) {
+    let _stdout = STDOUT.lock();
+    for &c in bytes {
+        console_putchar(c as usize);
+    }
+}
+
 pub unsafe fn print_no_lock(args: fmt::Arguments) {
     Stdout.write_fmt(args).unwrap();
 }
@@ -461,7 +461,6 @@ impl<'a, 'b> Iterator for EnumPropertiesIter<'a, 'b> {
     }
 }
 
-#[allow(dead_code)]
 #[repr(C)]
 struct DtbReserveEntry {
     address: u64,
@@ -475,11 +474,16 @@ use crate::mm::VirtualAddr;
 
 static mut FDT: mem::MaybeUninit<Dtb> = mem::MaybeUninit::<Dtb>::uninit();
 
-fn parse_u64(reg: &[u8]) -> u64 {
-    let h = u32::from_be(unsafe { *(reg.as_ptr() as *const u32) });
-    let l = u32::from_be(unsafe { *(reg[4..].as_ptr() as *const u32) });
+/// Parse a number of big endian cells
+fn parse_cells(data: &[u8]) -> u64 {
+    data.chunks_exact(4)
+        .fold(0, |n, cell| n << 32 | BigEndian::read_u32(cell) as u64)
+}
 
-    (h as u64) << 32 | l as u64
+/// Get `#address-cells` or `#size-cells` of `node`, or `default` if not set
+fn get_cells(dtb: &Dtb, node: &str, name: &str, default: usize) -> usize {
+    dtb.get_property(node, name)
+        .map_or(default, |cells| parse_cells(cells) as usize)
 }
 
 pub fn init_early(dtb: VirtualAddr) {
@@ -489,19 +493,77 @@ pub fn init_early(dtb: VirtualAddr) {
     };
 }
 
-pub fn get_memory() -> (PhysicalAddr, usize) {
+/// Call `f` with each `(start, size)` tuple of `reg` of `node`, whose
+/// number of cells are given by `parent`
+fn for_each_reg(dtb: &Dtb, parent: &str, node: &str, f: &mut impl FnMut(PhysicalAddr, usize)) {
+    let address_cells = get_cells(dtb, parent, "#address-cells", 2);
+    let size_cells = get_cells(dtb, parent, "#size-cells", 1);
+    let address_len = address_cells * mem::size_of::<u32>();
+    let tuple_len = address_len + size_cells * mem::size_of::<u32>();
+    if tuple_len == 0 {
+        return;
+    }
+    if let Some(reg) = dtb.get_property(node, "reg") {
+        for tuple in reg.chunks_exact(tuple_len) {
+            let start = parse_cells(&tuple[..address_len]);
+            let len = parse_cells(&tuple[address_len..]);
+            f(PhysicalAddr::new(start as usize), len as usize);
+        }
+    }
+}
+
+/// Call `f` with each range of all memory nodes
+pub fn for_each_memory(mut f: impl FnMut(PhysicalAddr, usize)) {
     let dtb = unsafe { FDT.assume_init_read() };
 
     for node in dtb.enum_subnodes("/") {
         if node.starts_with("memory") {
-            let reg = dtb.get_property(node, "reg").unwrap();
-            for i in 0..(reg.len() / 16) {
-                let start = parse_u64(&reg[i * 16..]);
-                let len = parse_u64(&reg[i * 16 + 8..]);
+            for_each_reg(&dtb, "/", node, &mut f);
+        }
+    }
+}
 
-                return (PhysicalAddr::new(start as usize), len as usize);
-            }
+/// Call `f` with each range which must not be used as normal memory,
+/// from the memory reservation block and children of `/reserved-memory`.
+/// `f` is also told whether the range has `no-map`, which must not be used
+/// as memory at all.
+/// Dynamically allocated reserved memory (without `reg`) is not supported.
+pub fn for_each_reserved(mut f: impl FnMut(PhysicalAddr, usize, bool)) {
+    let dtb = unsafe { FDT.assume_init_read() };
+
+    let header = dtb.header as *const DtbHeader as usize;
+    let mut entry =
+        (header + u32::from_be(dtb.header.off_mem_rsvmap) as usize) as *const DtbReserveEntry;
+    loop {
+        // The block is terminated by an entry of zero
+        let (address, size) =
+            unsafe { (u64::from_be((*entry).address), u64::from_be((*entry).size)) };
+        if address == 0 && size == 0 {
+            break;
         }
+        f(PhysicalAddr::new(address as usize), size as usize, false);
+        entry = unsafe { entry.add(1) };
+    }
+
+    let mut path = [0u8; 128];
+    for node in dtb.enum_subnodes("/reserved-memory") {
+        let prefix = b"reserved-memory/";
+        let len = prefix.len() + node.len();
+        assert!(len <= path.len(), "Too long dtb node {}", node);
+        path[..prefix.len()].copy_from_slice(prefix);
+        path[prefix.len()..len].copy_from_slice(node.as_bytes());
+        let path = unsafe { str::from_utf8_unchecked(&path[..len]) };
+        let no_map = dtb.get_property(path, "no-map").is_some();
+        for_each_reg(&dtb, "/reserved-memory", path, &mut |base, size| {
+            f(base, size, no_map)
+        });
     }
-    panic!("No memory in dtb");
+}
+
+/// Physical range of the dtb blob
+pub fn blob() -> (PhysicalAddr, usize) {
+    let dtb = unsafe { FDT.assume_init_read() };
+
+    let va = VirtualAddr::new(dtb.header as *const DtbHeader as usize);
+    (va.into(), u32::from_be(dtb.header.totalsize) as usize)
 }
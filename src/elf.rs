@@ -0,0 +1,160 @@
+//! ELF64 parser
+//!
+//! Only support little endian RISC-V executable (`ET_EXEC` or `ET_DYN`).
+
+use core::mem::size_of;
+use core::ptr;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+
+const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Data is shorter than header or segment
+    Truncated,
+    BadMagic,
+    /// Not 64-bit little endian
+    BadClass,
+    /// Not RISC-V
+    BadMachine,
+    /// Not `ET_EXEC` or `ET_DYN`
+    BadType,
+    /// Bad program header or segment
+    BadSegment,
+    /// Need an interpreter, dynamic link is not supported
+    Interpreter,
+    /// Can't alloc memory for segment or stack
+    NoMemory,
+    /// Arguments and environments are too long for user stack
+    ArgumentTooLong,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+/// Read a `T` from `data` at `offset`, `data` may be unaligned
+fn read<T: Copy>(data: &[u8], offset: usize) -> Result<T, ElfError> {
+    let end = offset
+        .checked_add(size_of::<T>())
+        .ok_or(ElfError::Truncated)?;
+    if end > data.len() {
+        return Err(ElfError::Truncated);
+    }
+    unsafe { Ok(ptr::read_unaligned(data[offset..].as_ptr() as *const T)) }
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> Elf<'a> {
+    /// Parse and validate ELF header
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header: ElfHeader = read(data, 0)?;
+
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != ELFCLASS64 || header.ident[5] != ELFDATA2LSB {
+            return Err(ElfError::BadClass);
+        }
+        if header.ident[6] != EV_CURRENT {
+            return Err(ElfError::BadMagic);
+        }
+        if header.machine != EM_RISCV {
+            return Err(ElfError::BadMachine);
+        }
+        if header.elf_type != ET_EXEC && header.elf_type != ET_DYN {
+            return Err(ElfError::BadType);
+        }
+        if header.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::BadSegment);
+        }
+
+        // make sure all program headers are in data
+        let phdr_size = header.phnum as u64 * size_of::<ProgramHeader>() as u64;
+        match header.phoff.checked_add(phdr_size) {
+            Some(end) if end <= data.len() as u64 => Ok(Self { data, header }),
+            _ => Err(ElfError::Truncated),
+        }
+    }
+
+    pub fn header(&self) -> &ElfHeader {
+        &self.header
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header.entry as usize
+    }
+
+    /// Position independent executable, should be loaded with a bias
+    pub fn is_dyn(&self) -> bool {
+        self.header.elf_type == ET_DYN
+    }
+
+    fn phdr_offset(&self, idx: usize) -> usize {
+        self.header.phoff as usize + idx * size_of::<ProgramHeader>()
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.header.phnum as usize)
+            .map(move |idx| read(self.data, self.phdr_offset(idx)).unwrap())
+    }
+
+    /// File data of segment `ph`
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = ph.offset as usize;
+        let end = start
+            .checked_add(ph.filesz as usize)
+            .ok_or(ElfError::BadSegment)?;
+        if ph.filesz > ph.memsz {
+            return Err(ElfError::BadSegment);
+        }
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+}
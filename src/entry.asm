@@ -47,6 +47,8 @@ spin:
     wfi
     j spin
 
+    # boot stack is only used in early boot, each hart switches to a kernel
+    # stack with guard pages before running tasks
     .section .data
     .align 4
 boot_stack:
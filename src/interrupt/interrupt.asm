@@ -24,13 +24,36 @@
     .section .text
     .globl __interrupt
 # entry of interrupt, save context and call interrupt handler
+#
+# sscratch is 0 when trap from kernel, and is the kernel stack top of
+# current task when trap from user. The word at kernel stack top saves
+# hart id (aka tp) of kernel.
 __interrupt:
+    csrrw   sp, sscratch, sp
+    bnez    sp, 1f
+    # trap from kernel, get back the kernel sp
+    csrr    sp, sscratch
+
+    # check kernel stack overflow before saving context, see `mm/kstack.rs`.
+    # Only sp is usable, sscratch keeps the kernel sp.
+    addi    sp, sp, -34*8
+    # kernel stacks are in the highest 1G
+    srai    sp, sp, 30
+    addi    sp, sp, 1
+    bnez    sp, 3f
+    csrr    sp, sscratch
+    addi    sp, sp, -34*8
+    # bit 14 is clear in guard pages
+    srli    sp, sp, 14
+    andi    sp, sp, 1
+    bnez    sp, 3f
+    j       __stack_overflow
+3:
+    csrr    sp, sscratch
+1:
     addi    sp, sp, -34*8
 
     SAVE    x1, 1
-    # save old sp(aka x2)
-    addi    x1, sp, 34*8
-    SAVE    x1, 2
     # save x3 to x31
     .set    n, 3
     .rept   29
@@ -38,11 +61,20 @@ __interrupt:
         .set    n, n + 1
     .endr
 
+    # save old sp(aka x2), and clear sscratch as we are in kernel now
+    csrrw   x1, sscratch, zero
+    SAVE    x1, 2
+
     csrr    s1, sstatus
     csrr    s2, sepc
     SAVE    s1, 32
     SAVE    s2, 33
 
+    # trap from user, load hart id of kernel
+    andi    t0, s1, 1 << 8
+    bnez    t0, 2f
+    LOAD    tp, 34
+2:
     # argument of interrupt_handler
     # context: &mut Context
     mv      a0, sp
@@ -60,10 +92,21 @@ __restore:
     csrw    sstatus, s1
     csrw    sepc, s2
 
+    andi    t0, s1, 1 << 8
+    bnez    t0, 1f
+    # return to user, save hart id and set sscratch to kernel stack top,
+    # then restore x4(aka tp) of user
+    SAVE    tp, 34
+    addi    t0, sp, 34*8
+    csrw    sscratch, t0
+    LOAD    x4, 4
+1:
     LOAD    x1, 1
-    # restore x3 to x31
-    .set    n, 3
-    .rept   29
+    LOAD    x3, 3
+    # restore x5 to x31, x4(aka tp) holds the hart id in kernel,
+    # task may be switched to another hart in interrupt handler
+    .set    n, 5
+    .rept   27
         LOAD_N  %n
         .set    n, n + 1
     .endr
@@ -71,3 +114,23 @@ __restore:
     # restore x2(aka sp)
     LOAD    x2, 2
     sret
+
+# kernel stack overflows, report it on emergency stack of this hart
+__stack_overflow:
+    csrw    sscratch, zero
+    # tp is hart id in kernel
+    la      sp, emergency_stack
+    addi    t0, tp, 1
+    slli    t0, t0, 14
+    add     sp, sp, t0
+
+    mv      a0, tp
+    csrr    a1, stval
+    csrr    a2, sepc
+    tail    kernel_stack_overflow
+
+    .section .bss
+    .align 12
+# 16K emergency stack per hart
+emergency_stack:
+    .space 16384*8
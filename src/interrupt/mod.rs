@@ -1,33 +1,177 @@
 //! Risc V Interrupt
 //!
 
-use core::arch::global_asm;
+use core::arch::{asm, global_asm};
 
 use crate::arch::{self, sstatus};
+use crate::mm::kstack;
+use crate::mm::vma::Access;
 
 global_asm!(include_str!("./interrupt.asm"));
 
+const INTERRUPT: usize = 1 << 63;
+const SUPERVISOR_SOFT: usize = INTERRUPT | 1;
+const SUPERVISOR_TIMER: usize = INTERRUPT | 5;
+const USER_ENV_CALL: usize = 8;
+const INSTRUCTION_PAGE_FAULT: usize = 12;
+const LOAD_PAGE_FAULT: usize = 13;
+const STORE_PAGE_FAULT: usize = 15;
+
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Context {
     pub regs: [usize; 32],
     pub sstatus: usize,
     pub sepc: usize,
 }
 
+impl Context {
+    /// Context to enter user mode at `entry` with user stack `sp`
+    pub fn new_user(entry: usize, sp: usize) -> Self {
+        let mut regs = [0; 32];
+        regs[2] = sp;
+        // return to user mode with interrupt enabled
+        let sstatus = unsafe { sstatus::read() };
+        let sstatus = (sstatus | sstatus::SSTATUS::SPIE as usize)
+            & !(sstatus::SSTATUS::SPP as usize)
+            & !(sstatus::SSTATUS::SIE as usize);
+        Self {
+            regs,
+            sstatus,
+            sepc: entry,
+        }
+    }
+
+    /// Whether trap from user mode
+    pub fn from_user(&self) -> bool {
+        self.sstatus & sstatus::SSTATUS::SPP as usize == 0
+    }
+}
+
 #[no_mangle]
 pub fn interrupt_handler(context: &mut Context, scause: usize, stval: usize) {
-    if scause == 0x8000000000000005 {
-        crate::timer::set_next_timeout();
+    if context.from_user() {
+        user_trap(context, scause, stval);
     } else {
+        kernel_trap(context, scause, stval);
+    }
+}
+
+/// Handle interrupt, return false if `scause` is not an interrupt
+fn handle_interrupt(scause: usize) -> bool {
+    match scause {
+        SUPERVISOR_TIMER => {
+            crate::timer::set_next_timeout();
+            crate::proc::scheduler_tick();
+        }
+        SUPERVISOR_SOFT => {
+            // IPI only used to wake up idle hart
+            arch::clear_software_interrupt();
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Memory access of page fault `scause`
+fn fault_access(scause: usize) -> Option<Access> {
+    match scause {
+        INSTRUCTION_PAGE_FAULT => Some(Access::Execute),
+        LOAD_PAGE_FAULT => Some(Access::Read),
+        STORE_PAGE_FAULT => Some(Access::Write),
+        _ => None,
+    }
+}
+
+/// Handle page fault of current user task, return false if it is a bad access
+fn user_page_fault(access: Access, stval: usize) -> bool {
+    let task = crate::proc::current();
+    let mut space = task.address_space();
+    match space.as_mut() {
+        Some(space) => space.handle_page_fault(stval, access),
+        None => false,
+    }
+}
+
+/// Called by `__interrupt` on emergency stack if kernel sp is in guard pages
+#[no_mangle]
+extern "C" fn kernel_stack_overflow(hart: usize, stval: usize, sepc: usize) -> ! {
+    panic!(
+        "kernel stack overflow on hart {}, stval: {:#x} in {:#x}",
+        hart, stval, sepc
+    );
+}
+
+fn kernel_trap(context: &mut Context, scause: usize, stval: usize) {
+    if let Some(access) = fault_access(scause) {
+        if kstack::is_guard(stval) {
+            kernel_stack_overflow(crate::proc::hartid(), stval, context.sepc);
+        }
+        panic!(
+            "Kernel oops: {:?} page fault at {:#x} in {:#x}",
+            access, stval, context.sepc
+        );
+    }
+    if !handle_interrupt(scause) {
         panic!(
             "Interrupted: {:#x?} stval: {:#x} in {:#x}",
             scause, stval, context.sepc
         );
-        //_context.sepc += 2;
     }
 }
 
+fn user_trap(context: &mut Context, scause: usize, stval: usize) {
+    if handle_interrupt(scause) {
+        return;
+    }
+    if scause == USER_ENV_CALL {
+        // return to the instruction after ecall
+        context.sepc += 4;
+        intr_on();
+        crate::syscall::syscall(context);
+        // `__restore` to user mode must not be interrupted
+        intr_off();
+    } else if let Some(access) = fault_access(scause) {
+        if !user_page_fault(access, stval) {
+            println!(
+                "Segmentation fault: {:?} at {:#x} in {:#x}, kill task {}",
+                access,
+                stval,
+                context.sepc,
+                crate::proc::current().tid()
+            );
+            crate::proc::exit(-1);
+        }
+    } else {
+        println!(
+            "User exception: {:#x?} stval: {:#x} in {:#x}, kill task {}",
+            scause,
+            stval,
+            context.sepc,
+            crate::proc::current().tid()
+        );
+        crate::proc::exit(-1);
+    }
+}
+
+/// Jump to `__restore` with `context` on the stack, used to enter user mode.
+///
+/// # SAFETY
+/// `context` must be at the kernel stack top of current task.
+pub unsafe fn restore(context: *mut Context) -> ! {
+    extern "C" {
+        fn __restore();
+    }
+    intr_off();
+    asm!(
+        "mv sp, {0}",
+        "jr {1}",
+        in(reg) context,
+        in(reg) __restore as usize,
+        options(noreturn)
+    );
+}
+
 #[inline]
 pub fn intr() -> bool {
     unsafe { sstatus::read() & sstatus::SSTATUS::SIE as usize != 0 }
@@ -56,6 +200,14 @@ pub fn init() {
             fn __interrupt();
         }
         arch::write_stvec(__interrupt as usize);
+        // we are in kernel, see `interrupt.asm`
+        arch::write_sscratch(0);
+
+        // enable software interrupt for IPI
+        asm!(
+            "li t0, 1<<1
+              csrs sie, t0"
+        );
 
         intr_on();
     }
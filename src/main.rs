@@ -3,6 +3,7 @@
 #![feature(panic_info_message)]
 #![feature(maybe_uninit_extra)]
 #![feature(const_fn_trait_bound)]
+#![feature(const_fn_fn_ptr_basics)]
 #![feature(default_alloc_error_handler)]
 #![feature(const_trait_impl)]
 #![feature(new_uninit)]
@@ -23,12 +24,14 @@ mod arch;
 mod console;
 #[allow(dead_code)]
 mod dtb;
+mod elf;
 mod interrupt;
 mod mm;
 mod panic;
 mod proc;
 mod sbi;
 mod sync;
+mod syscall;
 mod timer;
 
 global_asm!(include_str!("entry.asm"));
@@ -84,7 +87,44 @@ pub extern "C" fn rust_main(hart: usize, dtb: usize) -> ! {
             }
             println!("heap test passed");
         }
-        loop {}
+        {
+            let cache = mm::alloc::MemCache::create("boot-test", 24, 8, None);
+            let obj = cache
+                .alloc_obj([1usize, 2, 3])
+                .expect("No memory for slab object");
+            assert_eq!(unsafe { *obj.as_ptr() }, [1, 2, 3]);
+            unsafe {
+                cache.free_obj(obj);
+            }
+            println!("slab cache test passed");
+            #[cfg(feature = "mm-debug")]
+            mm::alloc::print_slabinfo();
+        }
+        {
+            use mm::alloc::{alloc_contiguous, free_contiguous, AllocFlags};
+            let frame = alloc_contiguous(3, AllocFlags::DMA32).expect("No DMA32 memory");
+            let end: mm::PhysicalAddr = mm::PageFrame::new(frame.get_ppn() + 3).into();
+            assert!(usize::from(end) <= 1 << 32);
+            free_contiguous(frame, 3);
+            println!("dma test passed");
+        }
+        {
+            use alloc::vec::Vec;
+            let handles: Vec<_> = (0..8).map(|i| proc::spawn(move || i)).collect();
+            proc::spawn(move || {
+                for (i, handle) in handles.into_iter().enumerate() {
+                    assert_eq!(handle.join(), i as isize);
+                }
+                println!("thread test passed in task {}", proc::current().tid());
+                0
+            });
+        }
+        proc::spawn(|| {
+            // built from user/hello.S by `make user`
+            let err = proc::exec(include_bytes!("../user/hello"), &["hello"], &[]);
+            panic!("exec hello failed: {:?}", err);
+        });
+        mm::kstack::run_on_kernel_stack(proc::run_tasks)
     } else {
         unsafe {
             while !STARTED.load(atomic::Ordering::Acquire) {
@@ -94,6 +134,7 @@ pub extern "C" fn rust_main(hart: usize, dtb: usize) -> ! {
         interrupt::init();
         proc::init(hart);
         // From now Percpu is available
+        timer::init();
         println!("Hart {} boot", hart);
 
         mm::init();
@@ -116,6 +157,6 @@ pub extern "C" fn rust_main(hart: usize, dtb: usize) -> ! {
             }
             println!("heap test passed");
         }
-        loop {}
+        mm::kstack::run_on_kernel_stack(proc::run_tasks)
     }
 }
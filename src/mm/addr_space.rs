@@ -0,0 +1,352 @@
+//! User address space
+//!
+//! Each user task has its own pagetable, which shares the kernel space
+//! with `KERNEL_PAGETABLE`. User memory is described by VMAs, and pages
+//! are allocated on first touch.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::{min, Ordering};
+use core::sync::atomic::{self, AtomicUsize};
+
+use super::alloc::{alloc_pages, put_pages};
+use super::mapping::asid::Asid;
+use super::mapping::pagetable::PageTable;
+use super::mapping::tlb::{flush_remote, flush_tlb};
+use super::mapping::Flags;
+use super::vma::{Access, Vma, VmaList};
+use super::{PageFrame, VirtualAddr, PAGE_MASK, PAGE_SHIFT, PAGE_SIZE};
+use crate::proc::hartid;
+
+/// End of user space in sv39
+pub const USER_SPACE_END: usize = 0x0000_0040_0000_0000;
+
+/// Leave a guard page at the end of user space
+pub const USER_STACK_TOP: usize = USER_SPACE_END - PAGE_SIZE;
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// `mmap` allocates address from here downward, leave a guard page
+/// below the user stack
+const MMAP_TOP: usize = USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE;
+
+pub struct AddressSpace {
+    page_table: Box<PageTable>,
+    asid: Asid,
+    /// Bitmap of harts running with this address space
+    active_harts: AtomicUsize,
+    vmas: VmaList,
+    /// Pages mapped to user, indexed by virtual page number
+    pages: BTreeMap<usize, PageFrame>,
+    /// Start of heap, end of the loaded program
+    heap_start: usize,
+    /// Current program break
+    brk: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        Self {
+            page_table: PageTable::new_user(),
+            asid: Asid::new(),
+            active_harts: AtomicUsize::new(0),
+            vmas: VmaList::new(),
+            pages: BTreeMap::new(),
+            heap_start: 0,
+            brk: 0,
+        }
+    }
+
+    /// Add a VMA of `start` ~ `end` with `flags`, pages are allocated on
+    /// first touch. Return false if it overlaps with others.
+    pub fn map_vma(&mut self, start: usize, end: usize, flags: Flags) -> bool {
+        let start = align_down!(start, PAGE_SIZE);
+        let end = align_up!(end, PAGE_SIZE);
+        if start >= end || end > USER_SPACE_END || self.vmas.overlaps(start, end) {
+            return false;
+        }
+        self.vmas.insert(Vma::new(start, end, flags | Flags::USER));
+        true
+    }
+
+    /// Map a zeroed page at `vpn` with `flags`
+    fn populate(&mut self, vpn: usize, flags: Flags) -> Option<PageFrame> {
+        let frame = alloc_pages(0)?;
+        unsafe {
+            frame.clear(0);
+        }
+        let va = VirtualAddr::new(vpn << PAGE_SHIFT);
+        if self
+            .page_table
+            .map(frame.into(), va, PAGE_SIZE, flags)
+            .is_err()
+        {
+            put_pages(frame, 0);
+            return None;
+        }
+        self.pages.insert(vpn, frame);
+        Some(frame)
+    }
+
+    /// Get page of `va`, alloc it if not touched yet. Return `None` if `va`
+    /// is not in a VMA which allows `access`, or out of memory.
+    ///
+    /// Permission is not checked if `access` is `None`.
+    fn get_page(&mut self, va: usize, access: Option<Access>) -> Option<PageFrame> {
+        let vma = *self.vmas.find(va)?;
+        if let Some(access) = access {
+            if !vma.allows(access) {
+                return None;
+            }
+        }
+        let vpn = va >> PAGE_SHIFT;
+        match self.pages.get(&vpn) {
+            Some(_) if access == Some(Access::Write) && self.is_cow(vpn) => {
+                self.break_cow(vpn, vma.flags)
+            }
+            Some(frame) => Some(*frame),
+            None => self.populate(vpn, vma.flags),
+        }
+    }
+
+    fn is_cow(&self, vpn: usize) -> bool {
+        let va = VirtualAddr::new(vpn << PAGE_SHIFT);
+        self.page_table
+            .translate(va)
+            .map_or(false, |(_, flags)| flags.contains(Flags::COW))
+    }
+
+    /// Give `vpn` a private copy of the shared page, and map it with `flags`
+    /// of VMA. The page is reused if it is not shared any more.
+    fn break_cow(&mut self, vpn: usize, flags: Flags) -> Option<PageFrame> {
+        let va = VirtualAddr::new(vpn << PAGE_SHIFT);
+        let frame = self.pages[&vpn];
+        if frame.ref_count() == 1 {
+            self.page_table.protect(va, PAGE_SIZE, flags).unwrap();
+            self.flush_remote(va, PAGE_SIZE);
+            return Some(frame);
+        }
+
+        let new_frame = alloc_pages(0)?;
+        unsafe {
+            let src: VirtualAddr = frame.into();
+            let dst: VirtualAddr = new_frame.into();
+            dst.as_ptr::<u8>()
+                .copy_from_nonoverlapping(src.as_ptr::<u8>(), PAGE_SIZE);
+        }
+        self.page_table.unmap(va, PAGE_SIZE).unwrap();
+        self.flush_remote(va, PAGE_SIZE);
+        self.pages.remove(&vpn);
+        put_pages(frame, 0);
+        // dir may be freed by unmap
+        if self
+            .page_table
+            .map(new_frame.into(), va, PAGE_SIZE, flags)
+            .is_err()
+        {
+            put_pages(new_frame, 0);
+            return None;
+        }
+        self.pages.insert(vpn, new_frame);
+        Some(new_frame)
+    }
+
+    /// Handle page fault at `va` caused by `access`, return false if it is
+    /// a bad access
+    pub fn handle_page_fault(&mut self, va: usize, access: Access) -> bool {
+        let vpn = va >> PAGE_SHIFT;
+        if self.pages.contains_key(&vpn) && !(access == Access::Write && self.is_cow(vpn)) {
+            // page is mapped with flags of VMA, so tlb is stale
+            return match self.vmas.find(va) {
+                Some(vma) if vma.allows(access) => {
+                    flush_tlb(VirtualAddr::new(vpn << PAGE_SHIFT));
+                    true
+                }
+                _ => false,
+            };
+        }
+        self.get_page(va, Some(access)).is_some()
+    }
+
+    /// Clone this address space, pages are shared copy-on-write. Writable
+    /// pages are mapped read-only in both address spaces, and copied on
+    /// the first write. Return `None` if out of memory.
+    pub fn fork(&mut self) -> Option<Self> {
+        let mut child = Self::new();
+        child.vmas = self.vmas.clone();
+        child.heap_start = self.heap_start;
+        child.brk = self.brk;
+
+        for (&vpn, &frame) in self.pages.iter() {
+            let va = VirtualAddr::new(vpn << PAGE_SHIFT);
+            let mut flags = self.vmas.find(va.into()).unwrap().flags;
+            if flags.contains(Flags::WRITABLE) {
+                flags = (flags - Flags::WRITABLE) | Flags::COW;
+                self.page_table.protect(va, PAGE_SIZE, flags).unwrap();
+            }
+            child
+                .page_table
+                .map(frame.into(), va, PAGE_SIZE, flags)
+                .ok()?;
+            frame.get_ref();
+            child.pages.insert(vpn, frame);
+        }
+        self.flush_remote(VirtualAddr::new(0), USER_SPACE_END);
+        Some(child)
+    }
+
+    /// Unmap `va` ~ `va + size`, remove it from VMAs and free pages
+    pub fn unmap(&mut self, va: usize, size: usize) {
+        let start = align_down!(va, PAGE_SIZE);
+        let end = align_up!(va + size, PAGE_SIZE);
+        self.vmas.remove(start, end);
+
+        let vpns: Vec<usize> = self
+            .pages
+            .range((start >> PAGE_SHIFT)..(end >> PAGE_SHIFT))
+            .map(|(vpn, _)| *vpn)
+            .collect();
+        for vpn in vpns {
+            let frame = self.pages.remove(&vpn).unwrap();
+            put_pages(frame, 0);
+        }
+        self.page_table
+            .unmap(VirtualAddr::new(start), end - start)
+            .expect("Bad user range");
+        self.flush_remote(VirtualAddr::new(start), end - start);
+    }
+
+    /// Copy between user address `va` and kernel buffer, page by page.
+    /// Return false if any page is not in a VMA which allows `access`.
+    fn copy_user<F>(&mut self, va: usize, len: usize, access: Option<Access>, mut f: F) -> bool
+    where
+        F: FnMut(*mut u8, usize, usize),
+    {
+        let mut va = va;
+        let mut done = 0;
+        while done < len {
+            let frame = match self.get_page(va, access) {
+                Some(frame) => frame,
+                None => return false,
+            };
+            let offset = va & PAGE_MASK;
+            let n = min(len - done, PAGE_SIZE - offset);
+
+            let page: VirtualAddr = frame.into();
+            f(unsafe { page.as_ptr::<u8>().add(offset) }, done, n);
+
+            done += n;
+            va += n;
+        }
+        true
+    }
+
+    /// Copy `data` to user address `va`, pages must allow `access`
+    fn copy_data(&mut self, va: usize, data: &[u8], access: Option<Access>) -> bool {
+        self.copy_user(va, data.len(), access, |ptr, off, n| unsafe {
+            ptr.copy_from_nonoverlapping(data[off..].as_ptr(), n);
+        })
+    }
+
+    /// Copy `data` to user address `va` ignoring permission, used by loader.
+    /// Return false if any page is not in a VMA.
+    pub fn write(&mut self, va: VirtualAddr, data: &[u8]) -> bool {
+        self.copy_data(va.into(), data, None)
+    }
+
+    /// Copy `data` to user address `va`, page must be user writable
+    pub fn copy_to_user(&mut self, va: usize, data: &[u8]) -> bool {
+        self.copy_data(va, data, Some(Access::Write))
+    }
+
+    /// Copy user address `va` to `buf`, page must be user readable
+    pub fn copy_from_user(&mut self, va: usize, buf: &mut [u8]) -> bool {
+        let access = Some(Access::Read);
+        self.copy_user(va, buf.len(), access, |ptr, off, n| unsafe {
+            buf[off..].as_mut_ptr().copy_from_nonoverlapping(ptr, n);
+        })
+    }
+
+    /// Set start of heap, should be called once after program loaded
+    pub fn init_heap(&mut self, start: usize) {
+        let start = align_up!(start, PAGE_SIZE);
+        self.heap_start = start;
+        self.brk = start;
+    }
+
+    /// Change program break to `brk`, return the new program break,
+    /// or current one if failed.
+    pub fn set_brk(&mut self, brk: usize) -> usize {
+        if brk < self.heap_start || brk > MMAP_TOP {
+            return self.brk;
+        }
+
+        let old_end = align_up!(self.brk, PAGE_SIZE);
+        let new_end = align_up!(brk, PAGE_SIZE);
+        match new_end.cmp(&old_end) {
+            Ordering::Greater => {
+                let flags = Flags::READABLE | Flags::WRITABLE;
+                if !self.map_vma(old_end, new_end, flags) {
+                    return self.brk;
+                }
+            }
+            Ordering::Less => self.unmap(new_end, old_end - new_end),
+            Ordering::Equal => {}
+        }
+        self.brk = brk;
+        brk
+    }
+
+    /// Map anonymous zeroed memory of `size` with `flags`.
+    ///
+    /// If `fixed`, map at `va` and replace existed pages, otherwise find a
+    /// free range. Return the start address.
+    pub fn mmap(&mut self, va: usize, size: usize, flags: Flags, fixed: bool) -> Option<usize> {
+        let size = align_up!(size, PAGE_SIZE);
+        let start = if fixed {
+            if va & PAGE_MASK != 0 || va.checked_add(size)? > USER_SPACE_END {
+                return None;
+            }
+            self.unmap(va, size);
+            va
+        } else {
+            let low = align_up!(self.brk, PAGE_SIZE).max(PAGE_SIZE);
+            self.vmas.find_free(size, low, MMAP_TOP)?
+        };
+
+        if self.map_vma(start, start + size, flags) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Flush tlb of `va` ~ `va + size` on other harts running with this
+    /// address space
+    fn flush_remote(&self, va: VirtualAddr, size: usize) {
+        let harts = self.active_harts.load(atomic::Ordering::Acquire);
+        flush_remote(harts, va, size, Some(self.asid.current()));
+    }
+
+    /// Switch to this address space, only tlb of its ASID is flushed
+    pub fn activate(&self) {
+        let (asid, flush_all) = self.asid.get();
+        self.active_harts
+            .fetch_or(1 << hartid(), atomic::Ordering::AcqRel);
+        self.page_table.load_asid(asid, flush_all);
+    }
+
+    /// Current hart is switching away from this address space
+    pub fn deactivate(&self) {
+        self.active_harts
+            .fetch_and(!(1 << hartid()), atomic::Ordering::AcqRel);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for frame in self.pages.values() {
+            put_pages(*frame, 0);
+        }
+    }
+}
@@ -1,12 +1,14 @@
 //! Buddy System Implemention
+//!
+//! State of free blocks is kept in `Page` of their head pages: the order and
+//! a free flag, and the block is linked to the free list of its order by
+//! `list_node`. So the buddy of a block is found from its page in O(1).
 
 use core::cmp::min;
 use core::mem::size_of_val;
 
-use super::linked_list::*;
-use crate::mm::PageFrame;
-use crate::mm::VirtualAddr;
-use crate::mm::PAGE_SHIFT;
+use super::double_linked_list::DoubleLinkedList;
+use crate::mm::{Page, PageFrame};
 
 macro_rules! prev_power_of_two {
     ($n: expr) => {{
@@ -14,14 +16,20 @@ macro_rules! prev_power_of_two {
     }};
 }
 
+const EMPTY_LIST: DoubleLinkedList = DoubleLinkedList::new();
+
 pub struct Buddy<const ORDER: usize> {
-    free_list: [LinkedList; ORDER],
+    /// Heads of free lists, must not be moved once a block is added
+    free_list: [DoubleLinkedList; ORDER],
+    /// Number of free blocks of each order
+    nr_free: [usize; ORDER],
 }
 
 impl<const ORDER: usize> Buddy<ORDER> {
     pub const fn new() -> Self {
         Self {
-            free_list: [LinkedList::new(); ORDER],
+            free_list: [EMPTY_LIST; ORDER],
+            nr_free: [0; ORDER],
         }
     }
 
@@ -39,53 +47,65 @@ impl<const ORDER: usize> Buddy<ORDER> {
             let ord = size.trailing_zeros() as usize;
             let ord = min(ord, self.free_list.len() - 1);
 
-            let addr: VirtualAddr = PageFrame::new(cur_start).into();
-            let addr: usize = addr.into();
-            self.free_list[ord as usize].push(addr as *mut usize);
+            self.free_pages(PageFrame::new(cur_start), ord);
             cur_start += 1 << (ord);
         }
     }
 
+    /// Number of free blocks of each order
+    pub fn nr_free(&self) -> [usize; ORDER] {
+        self.nr_free
+    }
+
+    /// Add free block `frame` of `ord` to free list
+    fn push(&mut self, frame: PageFrame, ord: usize) {
+        let page = unsafe { frame.get_page() };
+        page.order = ord as u8;
+        page.free = true;
+        self.free_list[ord].push(unsafe { &mut *page.list_node.get() });
+        self.nr_free[ord] += 1;
+    }
+
+    /// Remove free block `page` from free list
+    fn remove(&mut self, page: &mut Page) {
+        unsafe {
+            (*page.list_node.get()).remove();
+        }
+        page.free = false;
+        self.nr_free[page.order as usize] -= 1;
+    }
+
     pub fn alloc_pages(&mut self, ord: usize) -> Option<PageFrame> {
-        for i in ord..self.free_list.len() {
-            // Found the first not empty
-            if !self.free_list[i].empty() {
-                // Split pages
-                for j in (ord + 1..=i).rev() {
-                    let addr = self.free_list[j].pop().unwrap();
-                    self.free_list[j - 1]
-                        .push((addr as usize + (1 << (PAGE_SHIFT + j - 1))) as *mut usize);
-                    self.free_list[j - 1].push(addr);
-                }
-
-                let addr = VirtualAddr::new(self.free_list[ord].pop().unwrap() as usize);
-                return Some(addr.page_frame());
-            }
+        // Found the first not empty
+        let i = (ord..self.free_list.len()).find(|&i| !self.free_list[i].empty())?;
+        let node = self.free_list[i].remove_next().unwrap();
+        let page = unsafe { &mut *Page::from_list_node(node) };
+        page.free = false;
+        self.nr_free[i] -= 1;
+
+        // Split pages, put back the higher halves
+        let frame = page.get_frame();
+        for j in (ord..i).rev() {
+            self.push(PageFrame::new(frame.get_ppn() + (1 << j)), j);
         }
-        None
+        Some(frame)
     }
 
     pub fn free_pages(&mut self, pages: PageFrame, ord: usize) {
-        let cur_addr: VirtualAddr = pages.into();
-        let mut cur_addr: usize = cur_addr.into();
-        for ord in ord..self.free_list.len() {
-            let buddy = cur_addr ^ (1 << (PAGE_SHIFT + ord));
-            let mut found = false;
-
-            for m in self.free_list[ord].iter_mut() {
-                if m.data() as usize == buddy {
-                    m.remove();
-                    found = true;
-                    break;
-                }
-            }
+        let page = unsafe { pages.get_page() };
+        assert!(!page.free, "Free pages {:?} twice", pages);
 
-            if found {
-                cur_addr = min(cur_addr, buddy);
-            } else {
-                self.free_list[ord].push(cur_addr as *mut usize);
-                break;
+        let mut pfn = pages.get_ppn();
+        let mut ord = ord;
+        while ord < self.free_list.len() - 1 {
+            let buddy = PageFrame::new(pfn ^ (1 << ord));
+            match unsafe { buddy.get_page_checked() } {
+                Some(page) if page.free && page.order as usize == ord => self.remove(page),
+                _ => break,
             }
+            pfn = min(pfn, buddy.get_ppn());
+            ord += 1;
         }
+        self.push(PageFrame::new(pfn), ord);
     }
 }
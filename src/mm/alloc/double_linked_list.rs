@@ -1,6 +1,7 @@
 //! Double linked list
 //!
-//! This should only be used in slub
+//! This should only be used in slub and buddy system. A list is headed by a
+//! node which is not an element.
 
 use core::ptr;
 
@@ -17,6 +18,10 @@ impl DoubleLinkedList {
         }
     }
 
+    pub fn empty(&self) -> bool {
+        self.next.is_null()
+    }
+
     pub fn reset(&mut self) {
         self.next = ptr::null_mut();
         self.prev = ptr::null_mut();
@@ -56,6 +61,18 @@ impl DoubleLinkedList {
         return Some(node);
     }
 
+    /// Insert `node` after head `self`
+    pub fn push(&mut self, node: &mut DoubleLinkedList) {
+        node.prev = self;
+        node.next = self.next;
+        if !self.next.is_null() {
+            unsafe {
+                (*self.next).prev = node;
+            }
+        }
+        self.next = node;
+    }
+
     pub fn insert(&mut self, node: &mut DoubleLinkedList) {
         node.next = self;
         node.prev = self.prev;
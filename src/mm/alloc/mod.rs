@@ -4,30 +4,141 @@ mod buddy;
 mod double_linked_list;
 mod linked_list;
 mod slub;
+mod zone;
 
 pub use double_linked_list::DoubleLinkedList;
 pub use linked_list::LinkedList;
-pub use slub::MemCache;
+pub use slub::{for_each_cache, print_slabinfo, shrink_all, Ctor, MemCache, SlabInfo};
+pub use zone::{AllocFlags, ZoneType};
+
+use zone::zone;
 
 use crate::mm::PageFrame;
 use crate::mm::PhysicalAddr;
-use crate::sync::Spin;
 
-static BUDDY: Spin<buddy::Buddy<10>> = Spin::new(buddy::Buddy::new());
+/// Max order of buddy system is `MAX_ORDER - 1`
+pub const MAX_ORDER: usize = 10;
 
+/// Free memory to buddy systems of zones it belongs to
 pub fn free_to_buddy(addr: PhysicalAddr, len: usize) {
     println!("Free {:?} {:#x}", addr, len);
-    BUDDY
-        .lock()
-        .add_free_memory(addr.next_page_frame(), (addr + len).page_frame())
+    let start = addr.next_page_frame();
+    let end = (addr + len).page_frame();
+    for zone_type in ZoneType::ALL {
+        let (lo, hi) = zone_type.range();
+        let (start, end) = (start.max(lo), end.min(hi));
+        if start < end {
+            zone(zone_type).buddy.lock().add_free_memory(start, end);
+        }
+    }
+}
+
+/// Alloc 2^`ord` pages from zones allowed by `flags`. If there is no memory,
+/// drain page caches of all harts and retry, then shrink slub caches and retry.
+pub fn alloc_pages_flags(ord: usize, flags: AllocFlags) -> Option<PageFrame> {
+    let zones = ZoneType::fallbacks(flags);
+    let alloc = || {
+        zones
+            .iter()
+            .find_map(|&zone_type| zone(zone_type).alloc_pages(ord))
+    };
+    let frame = alloc()
+        .or_else(|| {
+            drain_all_pages();
+            alloc()
+        })
+        .or_else(|| {
+            if shrink_all() == 0 {
+                return None;
+            }
+            // slubs may be freed to page cache
+            drain_all_pages();
+            alloc()
+        })?;
+    unsafe {
+        frame.set_ref_count(1);
+    }
+    Some(frame)
 }
 
 pub fn alloc_pages(ord: usize) -> Option<PageFrame> {
-    BUDDY.lock().alloc_pages(ord)
+    alloc_pages_flags(ord, AllocFlags::empty())
 }
 
 pub fn free_pages(frame: PageFrame, ord: usize) {
-    BUDDY.lock().free_pages(frame, ord)
+    zone(ZoneType::of(frame)).free_pages(frame, ord)
+}
+
+/// Free pages cached by all harts to buddy systems
+pub fn drain_all_pages() {
+    for zone_type in ZoneType::ALL {
+        zone(zone_type).drain_all();
+    }
+}
+
+/// Free pages from `start` to `end` by the regular path, in largest aligned
+/// blocks
+fn free_range(start: PageFrame, end: PageFrame) {
+    let (mut pfn, end) = (start.get_ppn(), end.get_ppn());
+    while pfn < end {
+        let max_ord = (usize::BITS - 1 - (end - pfn).leading_zeros()) as usize;
+        let ord = (pfn.trailing_zeros() as usize)
+            .min(max_ord)
+            .min(MAX_ORDER - 1);
+        free_pages(PageFrame::new(pfn), ord);
+        pfn += 1 << ord;
+    }
+}
+
+/// Alloc `npages` physically contiguous pages for DMA, pages of the power of
+/// two block beyond `npages` are freed at once.
+///
+/// Each page is set up like an order-0 page from `alloc_pages`, with its own
+/// reference count, and the first page as head page.
+pub fn alloc_contiguous(npages: usize, flags: AllocFlags) -> Option<PageFrame> {
+    assert!(npages > 0, "Alloc zero contiguous pages");
+    let ord = npages.next_power_of_two().trailing_zeros() as usize;
+    if ord >= MAX_ORDER {
+        return None;
+    }
+    let frame = alloc_pages_flags(ord, flags)?;
+    let end = PageFrame::new(frame.get_ppn() + npages);
+    free_range(end, PageFrame::new(frame.get_ppn() + (1 << ord)));
+    unsafe {
+        frame.set_head_page(npages);
+        for page in frame..end {
+            page.set_ref_count(1);
+        }
+    }
+    Some(frame)
+}
+
+/// Drop references of pages allocated by `alloc_contiguous`, and free pages
+/// which are not referenced by others
+pub fn free_contiguous(frame: PageFrame, npages: usize) {
+    let end = PageFrame::new(frame.get_ppn() + npages);
+    let mut run = frame;
+    for page in frame..end {
+        if !page.put_ref() {
+            // still used, free pages before it
+            free_range(run, page);
+            run = page.next();
+        }
+    }
+    free_range(run, end);
+}
+
+/// Number of free blocks of each order in buddy system of `zone_type`
+#[cfg_attr(not(feature = "mm-debug"), allow(dead_code))]
+pub fn nr_free_blocks(zone_type: ZoneType) -> [usize; MAX_ORDER] {
+    zone(zone_type).buddy.lock().nr_free()
+}
+
+/// Drop a reference of pages, free them if it is the last one
+pub fn put_pages(frame: PageFrame, ord: usize) {
+    if frame.put_ref() {
+        free_pages(frame, ord);
+    }
 }
 
 #[cfg(test)]
@@ -1,20 +1,52 @@
 //! Slub memory allocator
+//!
+//! Besides the size classes of the global allocator, a subsystem can create
+//! a dedicated `MemCache` for its objects by `MemCache::create`. All caches
+//! are listed in a registry.
+//!
+//! # Debug
+//! With feature `slub-debug`, an object is put in a slot with metadata and
+//! red zones:
+//!
+//! | freelist | state | red zone | object | red zone |
+//!
+//! Red zones are checked when the object is allocated and freed, and a free
+//! object is poisoned and checked before allocated again. The state detects
+//! double free. Any corruption panics with the cache name and address.
 
 use super::{alloc_pages, free_pages};
 use super::{DoubleLinkedList, LinkedList};
+#[cfg(feature = "vmalloc-heap")]
+use crate::mm::vmalloc::{is_vmalloc, vfree, vmalloc};
 use crate::mm::{Page, PageFrame, VirtualAddr};
 use crate::mm::{PAGE_SHIFT, PAGE_SIZE};
-use crate::sync::{PerCpu, Spin};
+use crate::sync::{PerCpu, Spin, NCPU};
 
+use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
-use core::mem::{size_of, MaybeUninit};
+use core::mem::{align_of, size_of};
 use core::ops::DerefMut;
-use core::ptr;
-use core::sync::atomic::Ordering;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 const SLUB_MIN_OBJ: usize = 16;
 const SLUB_MAX_ORD: u16 = 3;
 
+/// Layouts larger than this are allocated by `vmalloc`, which needs no
+/// power of two pages
+#[cfg(feature = "vmalloc-heap")]
+const VMALLOC_MIN_SIZE: usize = 64 * PAGE_SIZE;
+
+const SLUB_DEBUG: bool = cfg!(feature = "slub-debug");
+/// Size of red zone after object, red zone before object is at least this
+const REDZONE_SIZE: usize = 8;
+const REDZONE: u8 = 0xbb;
+/// Pattern of free objects
+const POISON_FREE: u8 = 0x6b;
+/// State of a slot
+const SLOT_FREE: usize = 0xf4ee_f4ee_f4ee_f4ee;
+const SLOT_INUSE: usize = 0xa110_ca7e_a110_ca7e;
+
 const fn min(a: u16, b: u16) -> u16 {
     [a, b][(a > b) as usize]
 }
@@ -26,15 +58,6 @@ struct MemCacheCpu {
     freelist: LinkedList,
 }
 
-impl const Default for MemCacheCpu {
-    fn default() -> Self {
-        Self {
-            page: None,
-            freelist: LinkedList::new(),
-        }
-    }
-}
-
 impl MemCacheCpu {
     fn try_alloc(&mut self) -> *mut u8 {
         self.freelist.pop().unwrap_or(ptr::null_mut()) as *mut u8
@@ -51,6 +74,56 @@ impl MemCacheCpu {
     }
 }
 
+/// Cpu slub may be flushed by other harts when shrinking caches
+struct CpuSlub {
+    inner: Spin<MemCacheCpu>,
+}
+
+impl const Default for CpuSlub {
+    fn default() -> Self {
+        Self {
+            inner: Spin::new(MemCacheCpu {
+                page: None,
+                freelist: LinkedList::new(),
+            }),
+        }
+    }
+}
+
+/// Counters of a hart, may be read by other harts
+struct MemCacheStat {
+    allocs: AtomicUsize,
+    frees: AtomicUsize,
+}
+
+impl const Default for MemCacheStat {
+    fn default() -> Self {
+        Self {
+            allocs: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Statistics of a `MemCache`
+pub struct SlabInfo {
+    pub name: &'static str,
+    /// Size of object
+    pub size: usize,
+    pub objs_per_slab: usize,
+    pub pages_per_slab: usize,
+    pub slabs: usize,
+    pub partial_slabs: usize,
+    /// Allocated objects
+    pub active_objs: usize,
+    /// Objects of all slabs
+    pub total_objs: usize,
+    /// Alloc count of each hart
+    pub allocs: [usize; NCPU],
+    /// Free count of each hart
+    pub frees: [usize; NCPU],
+}
+
 struct MemCacheNode {
     nr_partial: usize,
     partial: DoubleLinkedList,
@@ -65,150 +138,472 @@ impl MemCacheNode {
     }
 }
 
+/// Constructor of objects, called on each allocation
+pub type Ctor = fn(*mut u8);
+
 pub struct MemCache {
-    cpu_slub: PerCpu<MemCacheCpu>,
+    name: &'static str,
+    ctor: Option<Ctor>,
+    cpu_slub: PerCpu<CpuSlub>,
+    stat: PerCpu<MemCacheStat>,
+    // num slubs allocated from buddy
+    nr_slabs: AtomicUsize,
     /* Global partial */
     node: Spin<MemCacheNode>,
     /* Following is const */
+    // size of slot
     size: usize,
+    obj_size: usize,
+    // offset of object in slot
+    offset: usize,
+    align: usize,
     // num pages to get from buddy
     ord: u16,
     // num obj per slub
     nobjs: u16,
     // set_min_partial
     min_partial: usize,
+    // next cache created by `MemCache::create`
+    next: AtomicPtr<MemCache>,
 }
 
-pub unsafe fn from_list_node(ln: *mut DoubleLinkedList) -> *mut Page {
-    let val: MaybeUninit<Page> = MaybeUninit::uninit();
-    let p = val.as_ptr();
-    let l = (*p).list_node.get();
-    let offset = l as usize - p as usize;
-
-    (ln as usize - offset) as *mut Page
-}
+/// Caches created by `MemCache::create`, linked by `next`. Registering and
+/// walking take no lock and never allocate, so they are safe when out of
+/// memory.
+static CACHES: AtomicPtr<MemCache> = AtomicPtr::new(ptr::null_mut());
 
 impl MemCache {
-    pub const fn new(obj_size: usize, align: usize) -> Self {
+    pub const fn new(
+        name: &'static str,
+        obj_size: usize,
+        align: usize,
+        ctor: Option<Ctor>,
+    ) -> Self {
         assert!(obj_size >= size_of::<usize>());
         assert!(align.is_power_of_two());
-        let size = align_up!(obj_size, align);
+        let obj_size = align_up!(obj_size, align);
+        let (offset, size) = if SLUB_DEBUG {
+            let offset = align_up!(2 * size_of::<usize>() + REDZONE_SIZE, align);
+            (offset, align_up!(offset + obj_size + REDZONE_SIZE, align))
+        } else {
+            (0, obj_size)
+        };
         let ord = (align_up!(size * SLUB_MIN_OBJ, PAGE_SIZE) >> PAGE_SHIFT)
             .next_power_of_two()
             .trailing_zeros() as u16;
         let ord = min(ord, SLUB_MAX_ORD);
         assert!(1 << ord << PAGE_SHIFT >= size);
         Self {
+            name,
+            ctor,
             cpu_slub: PerCpu::new(),
+            stat: PerCpu::new(),
+            nr_slabs: AtomicUsize::new(0),
             size,
+            obj_size,
+            offset,
+            align,
             ord,
             nobjs: ((1usize << ord << PAGE_SHIFT) / size) as u16,
             node: Spin::new(MemCacheNode::new()),
             min_partial: 8,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Create a cache of objects of `size` and `align` named `name`, `ctor`
+    /// initializes an object each time it is allocated
+    pub fn create(
+        name: &'static str,
+        size: usize,
+        align: usize,
+        ctor: Option<Ctor>,
+    ) -> &'static MemCache {
+        assert!(align <= PAGE_SIZE, "MemCache {} align too large", name);
+        let size = size.max(size_of::<usize>());
+        let cache = Box::leak(Box::new(MemCache::new(name, size, align, ctor)));
+        let mut head = CACHES.load(Ordering::Acquire);
+        loop {
+            cache.next.store(head, Ordering::Relaxed);
+            match CACHES.compare_exchange_weak(head, cache, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(new) => head = new,
+            }
+        }
+        cache
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Size of object, including padding for alignment
+    pub fn size(&self) -> usize {
+        self.obj_size
+    }
+
+    /// Alloc an object, return null if there is no memory
+    pub fn alloc(&self) -> *mut u8 {
+        let slot = self.alloc_raw();
+        if slot.is_null() {
+            return slot;
         }
+        if SLUB_DEBUG {
+            self.debug_alloc(slot as usize);
+        }
+        let obj = unsafe { slot.add(self.offset) };
+        self.stat.get().allocs.fetch_add(1, Ordering::Relaxed);
+        if let Some(ctor) = self.ctor {
+            ctor(obj);
+        }
+        obj
     }
 
-    pub fn alloc(&mut self) -> *mut u8 {
-        let self_ptr = self as *mut MemCache;
-        let mut cpu_slub = self.cpu_slub.get();
+    pub fn info(&self) -> SlabInfo {
+        let mut allocs = [0; NCPU];
+        let mut frees = [0; NCPU];
+        for hart in 0..NCPU {
+            // stat is only accessed by atomics
+            let stat = unsafe { self.stat.get_remote(hart) };
+            allocs[hart] = stat.allocs.load(Ordering::Relaxed);
+            frees[hart] = stat.frees.load(Ordering::Relaxed);
+        }
+        let active_objs = allocs
+            .iter()
+            .sum::<usize>()
+            .saturating_sub(frees.iter().sum());
+        let slabs = self.nr_slabs.load(Ordering::Relaxed);
+        SlabInfo {
+            name: self.name,
+            size: self.obj_size,
+            objs_per_slab: self.nobjs as usize,
+            pages_per_slab: 1 << self.ord,
+            slabs,
+            partial_slabs: self.node.lock().nr_partial,
+            active_objs,
+            total_objs: slabs * self.nobjs as usize,
+            allocs,
+            frees,
+        }
+    }
+
+    /// Free an object allocated from this cache
+    pub fn free(&self, ptr: *mut u8) {
+        let frame = unsafe { VirtualAddr::new(ptr as usize).page_frame().get_head_page() };
+        if SLUB_DEBUG
+            && unsafe { frame.get_page().slub } != self as *const MemCache as *mut MemCache
+        {
+            self.report("free object of other cache", ptr as usize);
+        }
+        self.dealloc(ptr, frame);
+    }
+
+    /// Alloc an object and move `value` into it
+    pub fn alloc_obj<T>(&self, value: T) -> Option<NonNull<T>> {
+        assert!(size_of::<T>() <= self.obj_size && align_of::<T>() <= self.align);
+        let obj = NonNull::new(self.alloc() as *mut T)?;
+        unsafe {
+            obj.as_ptr().write(value);
+        }
+        Some(obj)
+    }
+
+    /// Drop and free an object allocated by `alloc_obj`
+    ///
+    /// # Safety
+    /// `obj` must be allocated from this cache and not used after.
+    pub unsafe fn free_obj<T>(&self, obj: NonNull<T>) {
+        ptr::drop_in_place(obj.as_ptr());
+        self.free(obj.as_ptr() as *mut u8);
+    }
+
+    fn alloc_raw(&self) -> *mut u8 {
+        let cpu_slub = self.cpu_slub.get();
+        let mut cpu = cpu_slub.inner.lock();
 
         // fast path, alloc from cpu_slub
-        let ptr = cpu_slub.try_alloc();
+        let ptr = cpu.try_alloc();
         if !ptr.is_null() {
             // println!("Fast alloc");
             return ptr;
         }
 
-        // slow path, alloc from partial
+        // slow path, alloc from objects freed by other harts or partial
         {
             let mut node = self.node.lock();
 
+            if let Some(frame) = cpu.page {
+                let page = unsafe { frame.get_page() };
+                let mut free = page.freelist.lock();
+                if !free.empty() {
+                    cpu.freelist.swap(free.deref_mut());
+                    page.inuse.store(self.nobjs, Ordering::Relaxed);
+                    return cpu.try_alloc();
+                }
+            }
+            // current slub is full
+            self.unfreeze(&mut cpu, &mut node);
+
             if let Some(p) = node.partial.remove_next() {
                 // remove from partial
                 node.nr_partial -= 1;
 
-                let page = unsafe { &mut *from_list_node(p) };
+                let page = unsafe { &mut *Page::from_list_node(p) };
                 let mut free = page.freelist.lock();
-                cpu_slub.page = Some(page.get_frame());
+                page.frozen = true;
+                cpu.page = Some(page.get_frame());
                 // move all free obj to cpu
-                assert!(cpu_slub.freelist.empty());
-                cpu_slub.freelist.swap(free.deref_mut());
-                assert!(!cpu_slub.freelist.empty());
+                assert!(cpu.freelist.empty());
+                cpu.freelist.swap(free.deref_mut());
+                assert!(!cpu.freelist.empty());
 
                 page.inuse.store(self.nobjs, Ordering::Relaxed);
 
-                return cpu_slub.freelist.pop().unwrap() as *mut u8;
+                return cpu.try_alloc();
             }
         }
-        // very slow path, alloc new slub
-        if let Some(page) = alloc_pages(self.ord as usize) {
-            // init struct page
-            unsafe {
-                page.set_head_page(1 << self.ord);
 
-                let page = page.get_page();
-                page.inuse.store(self.nobjs, Ordering::Relaxed);
-                page.slub_data.objs = self.nobjs;
-                page.slub = self_ptr;
-                page.freelist.lock().reset();
-                page.list_node.lock().reset();
-            }
+        // very slow path, alloc new slub. Unlock cpu_slub as caches may be
+        // shrunk if there is no memory, cpu_slub.page is still empty after
+        drop(cpu);
+        let page = match alloc_pages(self.ord as usize) {
+            Some(page) => page,
+            None => return ptr::null_mut(),
+        };
+        self.nr_slabs.fetch_add(1, Ordering::Relaxed);
+        // init struct page
+        unsafe {
+            page.set_head_page(1 << self.ord);
+
+            let page = page.get_page();
+            page.inuse.store(self.nobjs, Ordering::Relaxed);
+            page.slub_data.objs = self.nobjs;
+            page.slub = self as *const MemCache as *mut MemCache;
+            page.frozen = true;
+            page.freelist.lock().reset();
+            page.list_node.lock().reset();
+        }
 
-            cpu_slub.page = Some(page);
+        let mut cpu = cpu_slub.inner.lock();
+        cpu.page = Some(page);
 
-            let addr: VirtualAddr = page.into();
-            let addr: usize = addr.into();
-            assert!(cpu_slub.freelist.empty());
-            for i in (0..(self.nobjs as usize)).rev() {
-                let obj = addr + i * self.size;
-                cpu_slub.freelist.push(obj as *mut usize);
+        let addr: VirtualAddr = page.into();
+        let addr: usize = addr.into();
+        assert!(cpu.freelist.empty());
+        for i in (0..(self.nobjs as usize)).rev() {
+            let slot = addr + i * self.size;
+            if SLUB_DEBUG {
+                self.debug_init(slot);
             }
-
-            // println!("Slow alloc ord-{}", self.ord);
-            return cpu_slub.freelist.pop().unwrap() as *mut u8;
-        } else {
-            return ptr::null_mut();
+            cpu.freelist.push(slot as *mut usize);
         }
+
+        // println!("Slow alloc ord-{}", self.ord);
+        cpu.try_alloc()
     }
 
-    pub fn dealloc(&mut self, ptr: *mut u8, frame: PageFrame) {
-        let mut cpu_slub = self.cpu_slub.get();
+    fn dealloc(&self, ptr: *mut u8, frame: PageFrame) {
+        let ptr = unsafe { ptr.sub(self.offset) };
+        if SLUB_DEBUG {
+            self.debug_free(ptr as usize, frame);
+        }
+        self.stat.get().frees.fetch_add(1, Ordering::Relaxed);
+        {
+            let cpu_slub = self.cpu_slub.get();
+            if cpu_slub.inner.lock().try_dealloc(ptr, frame) {
+                // println!("Fast dealloc");
+                return;
+            }
+        }
 
-        if cpu_slub.try_dealloc(ptr, frame) {
-            // println!("Fast dealloc");
+        let mut node = self.node.lock();
+        let page = unsafe { frame.get_page() };
+        let full;
+        {
+            let mut freelist = page.freelist.lock();
+            full = freelist.empty();
+            freelist.push(ptr as *mut usize);
+        }
+        let empty = page.inuse.fetch_sub(1, Ordering::Relaxed) == 1;
+        if page.frozen {
+            // cpu slub of other hart, it is unfrozen by the owner
             return;
         }
 
+        let mut lnode = page.list_node.lock();
+        if full {
+            // add to partial
+            node.partial.push(lnode.deref_mut());
+            node.nr_partial += 1;
+            // println!("Add to partial");
+        }
+        if empty && node.nr_partial > self.min_partial {
+            // free to buddy
+            lnode.remove();
+            node.nr_partial -= 1;
+            drop(lnode);
+            self.free_slub(frame);
+            // println!("Free slub to buddy");
+        }
+    }
+
+    fn free_slub(&self, frame: PageFrame) {
         unsafe {
-            let page = frame.get_page();
-            let full;
-            {
-                let mut freelist = page.freelist.lock();
-                full = freelist.empty();
-                freelist.push(ptr as *mut usize);
+            frame.get_page().slub = ptr::null_mut();
+        }
+        free_pages(frame, self.ord as usize);
+        self.nr_slabs.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Return the current slub of `cpu` to `node`, and move its free objects
+    /// in `cpu` back to the slub
+    fn unfreeze(&self, cpu: &mut MemCacheCpu, node: &mut MemCacheNode) {
+        let frame = match cpu.page.take() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let page = unsafe { frame.get_page() };
+        let mut nfree = 0;
+        let has_free = {
+            let mut freelist = page.freelist.lock();
+            while let Some(obj) = cpu.freelist.pop() {
+                freelist.push(obj);
+                nfree += 1;
             }
-            let empty = page.inuse.fetch_sub(1, Ordering::Acquire) == 1;
-
-            if empty {
-                let mut mnode = self.node.lock();
-                if mnode.nr_partial > self.min_partial {
-                    // free to buddy
-                    let mut lnode = page.list_node.lock();
-                    lnode.remove();
-                    mnode.nr_partial -= 1;
-
-                    free_pages(frame, page.slub_data.ord as usize);
-                    // println!("Free slub to buddy");
-                }
-            } else if full {
-                // add to partial
-                let mut mnode = self.node.lock();
-                let mut lnode = page.list_node.lock();
-                mnode.partial.insert(lnode.deref_mut());
-                mnode.nr_partial += 1;
-                // println!("Add to partial");
+            !freelist.empty()
+        };
+        page.inuse.fetch_sub(nfree, Ordering::Relaxed);
+        page.frozen = false;
+        if has_free {
+            node.partial.push(page.list_node.lock().deref_mut());
+            node.nr_partial += 1;
+        }
+    }
+
+    fn report(&self, msg: &str, addr: usize) -> ! {
+        panic!("slub {}: {} at {:#x}", self.name, msg, addr);
+    }
+
+    /// Red zones before and after object in `slot`
+    fn redzones(&self, slot: usize) -> [(usize, usize); 2] {
+        let obj = slot + self.offset;
+        [
+            (slot + 2 * size_of::<usize>(), obj),
+            (obj + self.obj_size, slot + self.size),
+        ]
+    }
+
+    fn state(slot: usize) -> *mut usize {
+        (slot as *mut usize).wrapping_add(1)
+    }
+
+    /// Fill `start` ~ `end` with `byte`
+    fn fill(start: usize, end: usize, byte: u8) {
+        unsafe {
+            (start as *mut u8).write_bytes(byte, end - start);
+        }
+    }
+
+    /// Check `start` ~ `end` is filled with `byte`, report `msg` if not
+    fn check(&self, start: usize, end: usize, byte: u8, msg: &str) {
+        let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, end - start) };
+        if let Some(pos) = bytes.iter().position(|&b| b != byte) {
+            self.report(msg, start + pos);
+        }
+    }
+
+    /// Init `slot` of a new slub as free
+    fn debug_init(&self, slot: usize) {
+        for (start, end) in self.redzones(slot) {
+            Self::fill(start, end, REDZONE);
+        }
+        let obj = slot + self.offset;
+        Self::fill(obj, obj + self.obj_size, POISON_FREE);
+        unsafe {
+            *Self::state(slot) = SLOT_FREE;
+        }
+    }
+
+    /// Check free `slot` before it is allocated
+    fn debug_alloc(&self, slot: usize) {
+        if unsafe { *Self::state(slot) } != SLOT_FREE {
+            self.report("freelist corrupted", slot + self.offset);
+        }
+        for (start, end) in self.redzones(slot) {
+            self.check(start, end, REDZONE, "red zone overwritten");
+        }
+        let obj = slot + self.offset;
+        self.check(
+            obj,
+            obj + self.obj_size,
+            POISON_FREE,
+            "free object modified",
+        );
+        unsafe {
+            *Self::state(slot) = SLOT_INUSE;
+        }
+    }
+
+    /// Check `slot` of slub `frame` before it is freed, and poison it
+    fn debug_free(&self, slot: usize, frame: PageFrame) {
+        let obj = slot.wrapping_add(self.offset);
+        let base: VirtualAddr = frame.into();
+        let idx = slot.wrapping_sub(base.into());
+        if idx % self.size != 0 || idx / self.size >= self.nobjs as usize {
+            self.report("free invalid pointer", obj);
+        }
+        match unsafe { *Self::state(slot) } {
+            SLOT_INUSE => {}
+            SLOT_FREE => self.report("double free", obj),
+            _ => self.report("free corrupted object", obj),
+        }
+        for (start, end) in self.redzones(slot) {
+            self.check(start, end, REDZONE, "red zone overwritten");
+        }
+        Self::fill(obj, obj + self.obj_size, POISON_FREE);
+        unsafe {
+            *Self::state(slot) = SLOT_FREE;
+        }
+    }
+
+    /// Flush cpu slubs of all harts, and free empty slubs to buddy system.
+    /// Return the number of pages freed.
+    pub fn shrink(&self) -> usize {
+        for hart in 0..NCPU {
+            // cpu slub is only accessed by its lock
+            let mut cpu = unsafe { self.cpu_slub.get_remote(hart) }.inner.lock();
+            let mut node = self.node.lock();
+            self.unfreeze(&mut cpu, &mut node);
+        }
+
+        let mut freed = 0;
+        let mut node = self.node.lock();
+        let mut keep = DoubleLinkedList::new();
+        while let Some(p) = node.partial.remove_next() {
+            let page = unsafe { &mut *Page::from_list_node(p) };
+            if page.inuse.load(Ordering::Relaxed) == 0 {
+                node.nr_partial -= 1;
+                self.free_slub(page.get_frame());
+                freed += 1 << self.ord;
+            } else {
+                keep.push(page.list_node.lock().deref_mut());
             }
         }
+        while let Some(p) = keep.remove_next() {
+            node.partial.push(unsafe { &mut *p });
+        }
+        freed
+    }
+}
+
+/// Largest power of two dividing `size`, objects of a size class are aligned
+/// to it
+const fn natural_align(size: usize) -> usize {
+    let align = size & size.wrapping_neg();
+    if align > PAGE_SIZE {
+        PAGE_SIZE
+    } else {
+        align
     }
 }
 
@@ -225,7 +620,7 @@ macro_rules! init_slub {
     };
     (@expand_slub $($info:expr), +) => {
         [
-            $(MemCache::new($info, size_of::<usize>()),)*
+            $(MemCache::new(concat!("kmalloc-", $info), $info, natural_align($info), None),)*
         ]
     };
     ($($size:expr), *) => {
@@ -243,14 +638,18 @@ struct Slub {}
 static SLUB_ALLOCATOR: Slub = Slub {};
 
 impl Slub {
-    fn _alloc(size: usize) -> *mut u8 {
-        if size <= SLUB_INFO[SLUB_LEN - 1] {
-            let slub = match SLUB_INFO.binary_search(&size) {
-                Ok(n) => n,
-                Err(n) => n,
-            };
+    /// Size class for `size` aligned to `align`
+    fn size_class(size: usize, align: usize) -> Option<usize> {
+        SLUB_INFO
+            .iter()
+            .position(|&class| class >= size && natural_align(class) >= align)
+    }
+
+    fn _alloc(size: usize, align: usize) -> *mut u8 {
+        if let Some(slub) = Self::size_class(size, align) {
             unsafe { SLUB[slub].alloc() }
         } else {
+            // buddy block is aligned to its size, which is not less than align
             let ord = (align_up!(size, PAGE_SIZE) >> PAGE_SHIFT)
                 .next_power_of_two()
                 .trailing_zeros() as u16;
@@ -260,6 +659,7 @@ impl Slub {
                     pages.set_head_page(1 << ord);
 
                     let page = pages.get_page();
+                    page.slub = ptr::null_mut();
                     page.slub_data.ord = ord;
                 }
                 let addr: VirtualAddr = pages.into();
@@ -272,10 +672,36 @@ impl Slub {
         }
     }
 
+    /// Size can be used in place of `ptr`, `None` if unknown
+    fn usable_size(ptr: *mut u8) -> Option<usize> {
+        let addr = VirtualAddr::new(ptr as usize);
+
+        #[cfg(feature = "vmalloc-heap")]
+        if is_vmalloc(addr) {
+            return None;
+        }
+
+        unsafe {
+            let frame = addr.page_frame().get_head_page();
+            let page = frame.get_page();
+            if page.slub.is_null() {
+                Some(PAGE_SIZE << page.slub_data.ord)
+            } else {
+                Some((*page.slub).obj_size)
+            }
+        }
+    }
+
     fn _dealloc(ptr: *mut u8) {
         let addr = ptr as usize;
         let addr = VirtualAddr::new(addr);
 
+        #[cfg(feature = "vmalloc-heap")]
+        if is_vmalloc(addr) {
+            vfree(addr);
+            return;
+        }
+
         unsafe {
             let frame = addr.page_frame().get_head_page();
             let page = frame.get_page();
@@ -298,10 +724,74 @@ unsafe impl GlobalAlloc for Slub {
         let align = layout.align();
         let obj_size = align_up!(size, align);
 
-        Slub::_alloc(obj_size)
+        #[cfg(feature = "vmalloc-heap")]
+        if obj_size > VMALLOC_MIN_SIZE && align <= PAGE_SIZE {
+            return vmalloc(obj_size).map_or(ptr::null_mut(), |va| va.as_ptr());
+        }
+
+        Slub::_alloc(obj_size, align)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
         Slub::_dealloc(ptr);
     }
+
+    /// Grow or shrink in place if `new_size` still fits in the object or
+    /// pages of `ptr`, otherwise move to a new allocation
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if Slub::usable_size(ptr).map_or(false, |size| size >= new_size) {
+            return ptr;
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// Call `f` on each cache, including size classes of the global allocator
+pub fn for_each_cache(mut f: impl FnMut(&MemCache)) {
+    unsafe {
+        SLUB.iter().for_each(&mut f);
+    }
+    let mut cache = CACHES.load(Ordering::Acquire);
+    while let Some(c) = unsafe { cache.as_ref() } {
+        f(c);
+        cache = c.next.load(Ordering::Acquire);
+    }
+}
+
+/// Print statistics of all caches like /proc/slabinfo
+#[cfg_attr(not(feature = "mm-debug"), allow(dead_code))]
+pub fn print_slabinfo() {
+    println!(
+        "{:<16} {:>8} {:>8} {:>6} {:>6} {:>6} {:>6} {:>7} {:>8} {:>8}",
+        "name", "active", "total", "size", "objs", "pages", "slabs", "partial", "allocs", "frees"
+    );
+    for_each_cache(|cache| {
+        let info = cache.info();
+        println!(
+            "{:<16} {:>8} {:>8} {:>6} {:>6} {:>6} {:>6} {:>7} {:>8} {:>8}",
+            info.name,
+            info.active_objs,
+            info.total_objs,
+            info.size,
+            info.objs_per_slab,
+            info.pages_per_slab,
+            info.slabs,
+            info.partial_slabs,
+            info.allocs.iter().sum::<usize>(),
+            info.frees.iter().sum::<usize>()
+        );
+    });
+}
+
+/// Shrink all caches, return the number of pages freed
+pub fn shrink_all() -> usize {
+    let mut freed = 0;
+    for_each_cache(|cache| freed += cache.shrink());
+    freed
 }
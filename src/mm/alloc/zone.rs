@@ -0,0 +1,180 @@
+//! Memory zones
+//!
+//! Physical memory is split to zones by address, each zone has its own buddy
+//! system. An allocation tries zones allowed by its flags from the preferred
+//! one, and falls back to lower zones.
+//!
+//! Each hart caches some free order-0 pages of a zone, which are refilled
+//! from and drained to buddy system in batch, so most single page allocations
+//! don't take the lock of buddy system.
+
+use bitflags::bitflags;
+
+use super::buddy::Buddy;
+use super::linked_list::LinkedList;
+use super::MAX_ORDER;
+use crate::mm::{PageFrame, VirtualAddr};
+use crate::sync::{PerCpu, Spin, NCPU};
+
+/// Number of pages moved between page cache and buddy system at a time
+const PCP_BATCH: usize = 16;
+/// Max pages in page cache of a hart, half of them are drained if exceeded
+const PCP_HIGH: usize = 4 * PCP_BATCH;
+
+/// First page frame above 4G
+const DMA32_END: usize = 1 << (32 - crate::mm::PAGE_SHIFT);
+
+bitflags! {
+    pub struct AllocFlags: u8 {
+        /// Alloc below 4G, for devices which can only address 32 bits
+        const DMA32 = 1 << 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneType {
+    /// Memory below 4G
+    Dma32,
+    /// Memory above 4G
+    Normal,
+}
+
+pub const NR_ZONES: usize = 2;
+
+impl ZoneType {
+    pub const ALL: [ZoneType; NR_ZONES] = [ZoneType::Dma32, ZoneType::Normal];
+
+    /// Zone which `frame` belongs to
+    pub fn of(frame: PageFrame) -> Self {
+        if frame.get_ppn() < DMA32_END {
+            ZoneType::Dma32
+        } else {
+            ZoneType::Normal
+        }
+    }
+
+    /// Zones to try for allocation with `flags`, in order
+    pub fn fallbacks(flags: AllocFlags) -> &'static [ZoneType] {
+        if flags.contains(AllocFlags::DMA32) {
+            &[ZoneType::Dma32]
+        } else {
+            &[ZoneType::Normal, ZoneType::Dma32]
+        }
+    }
+
+    #[cfg_attr(not(feature = "mm-debug"), allow(dead_code))]
+    pub fn name(self) -> &'static str {
+        match self {
+            ZoneType::Dma32 => "DMA32",
+            ZoneType::Normal => "Normal",
+        }
+    }
+
+    /// Page frames of zone, `end` is exclusive
+    pub fn range(self) -> (PageFrame, PageFrame) {
+        match self {
+            ZoneType::Dma32 => (PageFrame::new(0), PageFrame::new(DMA32_END)),
+            ZoneType::Normal => (PageFrame::new(DMA32_END), PageFrame::new(usize::MAX)),
+        }
+    }
+}
+
+/// Free order-0 pages cached by a hart
+struct PageList {
+    pages: LinkedList,
+    count: usize,
+}
+
+impl PageList {
+    fn push(&mut self, frame: PageFrame) {
+        let addr: VirtualAddr = frame.into();
+        self.pages.push(addr.as_ptr());
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<PageFrame> {
+        let addr = self.pages.pop()?;
+        self.count -= 1;
+        Some(VirtualAddr::new(addr as usize).page_frame())
+    }
+}
+
+struct PageCache {
+    /// Locked by owner hart, or other harts draining it
+    list: Spin<PageList>,
+}
+
+impl const Default for PageCache {
+    fn default() -> Self {
+        Self {
+            list: Spin::new(PageList {
+                pages: LinkedList::new(),
+                count: 0,
+            }),
+        }
+    }
+}
+
+pub struct Zone {
+    pub buddy: Spin<Buddy<MAX_ORDER>>,
+    page_cache: PerCpu<PageCache>,
+}
+
+impl Zone {
+    const fn new() -> Self {
+        Self {
+            buddy: Spin::new(Buddy::new()),
+            page_cache: PerCpu::new(),
+        }
+    }
+
+    /// Alloc 2^`ord` pages, order-0 pages are taken from page cache
+    pub fn alloc_pages(&self, ord: usize) -> Option<PageFrame> {
+        if ord != 0 {
+            return self.buddy.lock().alloc_pages(ord);
+        }
+        let cache = self.page_cache.get();
+        let mut list = cache.list.lock();
+        if list.count == 0 {
+            let mut buddy = self.buddy.lock();
+            for frame in (0..PCP_BATCH).map_while(|_| buddy.alloc_pages(0)) {
+                list.push(frame);
+            }
+        }
+        list.pop()
+    }
+
+    /// Free 2^`ord` pages, order-0 pages are put to page cache
+    pub fn free_pages(&self, frame: PageFrame, ord: usize) {
+        if ord != 0 {
+            return self.buddy.lock().free_pages(frame, ord);
+        }
+        let cache = self.page_cache.get();
+        let mut list = cache.list.lock();
+        list.push(frame);
+        if list.count > PCP_HIGH {
+            let mut buddy = self.buddy.lock();
+            for _ in 0..PCP_HIGH / 2 {
+                buddy.free_pages(list.pop().unwrap(), 0);
+            }
+        }
+    }
+
+    /// Free pages cached by all harts to buddy system
+    pub fn drain_all(&self) {
+        for hart in 0..NCPU {
+            // page cache is only accessed by its lock
+            let mut list = unsafe { self.page_cache.get_remote(hart) }.list.lock();
+            let mut buddy = self.buddy.lock();
+            while let Some(frame) = list.pop() {
+                buddy.free_pages(frame, 0);
+            }
+        }
+    }
+}
+
+static ZONES: [Zone; NR_ZONES] = [Zone::new(), Zone::new()];
+
+pub fn zone(zone_type: ZoneType) -> &'static Zone {
+    &ZONES[zone_type as usize]
+}
@@ -0,0 +1,158 @@
+//! Kernel stacks with guard pages
+//!
+//! Kernel stacks are mapped in the highest 1G of kernel space, which is split
+//! to slots of twice the stack size. A stack is mapped in the upper half of
+//! its slot and the lower half is left unmapped as guard pages, so a stack
+//! overflow faults instead of corrupting the stack below.
+//!
+//! `__interrupt` relies on this layout to check whether the kernel sp is in
+//! guard pages, and reports the overflow on a per-hart emergency stack.
+
+use core::arch::asm;
+use core::mem;
+
+use super::alloc::{alloc_pages, free_pages};
+use super::mapping::tlb::flush_kernel;
+use super::mapping::{Flags, KERNEL_PAGETABLE};
+use super::{PageFrame, VirtualAddr, PAGE_SIZE};
+use crate::sync::Spin;
+
+/// Start of kernel stack region, which ends at the top of address space,
+/// see `interrupt.asm`
+const KSTACK_BASE: usize = 0xffff_ffff_c000_0000;
+const KSTACK_REGION_SIZE: usize = 0usize.wrapping_sub(KSTACK_BASE);
+
+/// Kernel stack is 16K
+const KSTACK_ORD: usize = 2;
+const KSTACK_SIZE: usize = PAGE_SIZE << KSTACK_ORD;
+
+/// A slot is guard pages followed by a stack, so bit 14 of address is clear
+/// in guard pages and set in the stack, see `interrupt.asm`
+const SLOT_SIZE: usize = KSTACK_SIZE * 2;
+const NR_SLOTS: usize = KSTACK_REGION_SIZE / SLOT_SIZE;
+
+struct Slots {
+    /// Bitmap of used slots
+    used: [u64; NR_SLOTS / 64],
+    /// Next slot to try
+    next: usize,
+}
+
+impl Slots {
+    const fn new() -> Self {
+        Self {
+            used: [0; NR_SLOTS / 64],
+            next: 0,
+        }
+    }
+
+    fn is_used(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        let slot = (self.next..NR_SLOTS)
+            .chain(0..self.next)
+            .find(|&slot| !self.is_used(slot))?;
+        self.used[slot / 64] |= 1 << (slot % 64);
+        self.next = (slot + 1) % NR_SLOTS;
+        Some(slot)
+    }
+
+    fn free(&mut self, slot: usize) {
+        self.used[slot / 64] &= !(1 << (slot % 64));
+    }
+}
+
+/// Also protects kernel stack region of `KERNEL_PAGETABLE`
+static SLOTS: Spin<Slots> = Spin::new(Slots::new());
+
+/// Start of the stack in `slot`, after its guard pages
+fn stack_start(slot: usize) -> VirtualAddr {
+    VirtualAddr::new(KSTACK_BASE + slot * SLOT_SIZE + KSTACK_SIZE)
+}
+
+/// Whether `va` is in guard pages of kernel stacks
+pub fn is_guard(va: usize) -> bool {
+    va >= KSTACK_BASE && va & KSTACK_SIZE == 0
+}
+
+pub struct KernelStack {
+    slot: usize,
+    frame: PageFrame,
+}
+
+impl KernelStack {
+    /// Alloc a kernel stack, return `None` if out of memory
+    pub fn new() -> Option<Self> {
+        let frame = alloc_pages(KSTACK_ORD)?;
+        let mut slots = SLOTS.lock();
+        let slot = match slots.alloc() {
+            Some(slot) => slot,
+            None => {
+                free_pages(frame, KSTACK_ORD);
+                return None;
+            }
+        };
+        let pagetable = unsafe { &mut KERNEL_PAGETABLE };
+        let flags = Flags::READABLE | Flags::WRITABLE | Flags::GLOBAL;
+        if pagetable
+            .map(frame.into(), stack_start(slot), KSTACK_SIZE, flags)
+            .is_err()
+        {
+            slots.free(slot);
+            free_pages(frame, KSTACK_ORD);
+            return None;
+        }
+        Some(Self { slot, frame })
+    }
+
+    pub fn top(&self) -> usize {
+        let start: usize = stack_start(self.slot).into();
+        start + KSTACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let start = stack_start(self.slot);
+        {
+            let mut slots = SLOTS.lock();
+            let pagetable = unsafe { &mut KERNEL_PAGETABLE };
+            pagetable
+                .unmap(start, KSTACK_SIZE)
+                .expect("Bad kernel stack");
+            // stack may be cached by other harts
+            flush_kernel(start, KSTACK_SIZE);
+            slots.free(self.slot);
+        }
+        free_pages(self.frame, KSTACK_ORD);
+    }
+}
+
+/// Switch current hart from boot stack to a new kernel stack and call `f`,
+/// the stack is never freed
+pub fn run_on_kernel_stack(f: fn() -> !) -> ! {
+    let stack = KernelStack::new().expect("No memory for kernel stack");
+    let top = stack.top();
+    mem::forget(stack);
+    unsafe {
+        asm!(
+            "mv sp, {0}",
+            "jr {1}",
+            in(reg) top,
+            in(reg) f as usize,
+            options(noreturn)
+        );
+    }
+}
+
+/// Alloc root dir of kernel stack region before any user pagetable is
+/// created, so that stacks are mapped in all pagetables
+pub(super) fn init_early() {
+    unsafe {
+        KERNEL_PAGETABLE
+            .alloc_root_dir(VirtualAddr::new(KSTACK_BASE))
+            .expect("No memory for kernel stack region");
+    }
+}
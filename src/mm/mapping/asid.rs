@@ -0,0 +1,165 @@
+//! Address space identifier (ASID) allocator
+//!
+//! ASID is allocated by generation. An address space keeps its ASID until
+//! all ASIDs of current generation are used up, then a new generation begins
+//! and all harts must flush the whole TLB before using a new ASID, as the
+//! same ASID may be still cached for an address space of old generation.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::proc::hartid;
+use crate::sync::{Spin, NCPU};
+
+/// ASID of `KERNEL_PAGETABLE`, never allocated to user
+pub const KERNEL_ASID: usize = 0;
+
+/// Sv39 supports at most 16 bits ASID
+const MAX_ASID_BITS: usize = 16;
+const MAX_ASIDS: usize = 1 << MAX_ASID_BITS;
+
+/// Bit offset of ASID field in `satp`
+const SATP_ASID_SHIFT: usize = 44;
+
+struct AsidAllocator {
+    /// Number of ASID bits implemented by hardware
+    bits: usize,
+    generation: usize,
+    /// Bitmap of ASIDs used in current generation
+    used: [u64; MAX_ASIDS / 64],
+    /// Next ASID to try
+    next: usize,
+    /// Bitmap of harts which should flush whole TLB before next switch
+    flush_pending: usize,
+}
+
+impl AsidAllocator {
+    const fn new() -> Self {
+        Self {
+            bits: 0,
+            generation: 1,
+            used: [0; MAX_ASIDS / 64],
+            next: KERNEL_ASID + 1,
+            flush_pending: 0,
+        }
+    }
+
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn set_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+
+    /// Begin a new generation, all harts should flush TLB
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.used = [0; MAX_ASIDS / 64];
+        self.set_used(KERNEL_ASID);
+        self.next = KERNEL_ASID + 1;
+        self.flush_pending = (1 << NCPU) - 1;
+    }
+
+    /// Alloc an ASID of current generation, return the context id
+    fn alloc(&mut self) -> usize {
+        let nr = 1 << self.bits;
+        let found = (self.next..nr).find(|&asid| !self.is_used(asid));
+        let asid = match found {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                self.next
+            }
+        };
+        self.set_used(asid);
+        self.next = asid + 1;
+        context_id(self.generation, asid)
+    }
+}
+
+static ALLOCATOR: Spin<AsidAllocator> = Spin::new(AsidAllocator::new());
+
+/// Context id combines generation and ASID
+fn context_id(generation: usize, asid: usize) -> usize {
+    generation << MAX_ASID_BITS | asid
+}
+
+fn generation(context_id: usize) -> usize {
+    context_id >> MAX_ASID_BITS
+}
+
+fn asid(context_id: usize) -> usize {
+    context_id & (MAX_ASIDS - 1)
+}
+
+/// ASID of an address space
+pub struct Asid {
+    /// Context id of last allocated ASID, 0 if not allocated
+    context_id: AtomicUsize,
+}
+
+impl Asid {
+    pub const fn new() -> Self {
+        Self {
+            context_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// ASID allocated last time, may be of old generation
+    pub fn current(&self) -> usize {
+        asid(self.context_id.load(Ordering::Relaxed))
+    }
+
+    /// Get an ASID of current generation for switching to the address space,
+    /// alloc a new one if it is of old generation.
+    ///
+    /// Return the ASID, and whether current hart should flush whole TLB.
+    pub fn get(&self) -> (usize, bool) {
+        let mut allocator = ALLOCATOR.lock();
+        if allocator.bits == 0 {
+            // ASID not supported, all address spaces use the same one
+            return (KERNEL_ASID, true);
+        }
+
+        let mut id = self.context_id.load(Ordering::Relaxed);
+        if id == 0 || generation(id) != allocator.generation {
+            id = allocator.alloc();
+            self.context_id.store(id, Ordering::Relaxed);
+        }
+
+        let mask = 1 << hartid();
+        let flush_all = allocator.flush_pending & mask != 0;
+        allocator.flush_pending &= !mask;
+        (asid(id), flush_all)
+    }
+}
+
+/// Detect number of ASID bits by writing all ones to ASID field of `satp`
+fn detect_asid_bits() -> usize {
+    let mask = (MAX_ASIDS - 1) << SATP_ASID_SHIFT;
+    let probe: usize;
+    unsafe {
+        asm!(
+            "csrr {satp}, satp",
+            "or {probe}, {satp}, {mask}",
+            "csrw satp, {probe}",
+            "csrr {probe}, satp",
+            "csrw satp, {satp}",
+            satp = out(reg) _,
+            probe = out(reg) probe,
+            mask = in(reg) mask,
+        );
+    }
+    ((probe & mask) >> SATP_ASID_SHIFT).count_ones() as usize
+}
+
+pub(super) fn init() {
+    let bits = detect_asid_bits();
+    let mut allocator = ALLOCATOR.lock();
+    if allocator.bits == 0 && bits > 0 {
+        allocator.bits = bits;
+        allocator.set_used(KERNEL_ASID);
+        println!("ASID: {} bits", bits);
+    }
+}
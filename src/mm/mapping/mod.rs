@@ -1,7 +1,9 @@
 //! Page table mapping
 
+pub mod asid;
 pub mod pagetable;
 mod pte;
+pub mod tlb;
 
 use bit_field::BitField;
 
@@ -44,18 +46,30 @@ pub(super) fn init_early() {
 
     {
         let mut pagetable = PageTable::new();
-        pagetable.map(
-            PhysicalAddr::new(0x8000_0000),
-            VirtualAddr::new(0xffffffc0_80000000),
-            4096 * 5,
-            Flags::READABLE,
-        );
+        pagetable
+            .map(
+                PhysicalAddr::new(0x8000_0000),
+                VirtualAddr::new(0xffffffc0_80000000),
+                4096 * 5,
+                Flags::READABLE,
+            )
+            .unwrap();
         println!("{:#?}", pagetable);
     }
 }
 
 pub(super) fn init() {
+    asid::init();
+    tlb::init();
     unsafe {
         KERNEL_PAGETABLE.load();
     }
 }
+
+/// Switch to kernel pagetable, kernel mappings are global so they are
+/// kept in tlb
+pub fn load_kernel_pagetable() {
+    unsafe {
+        KERNEL_PAGETABLE.load_asid(asid::KERNEL_ASID, false);
+    }
+}
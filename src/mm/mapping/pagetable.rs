@@ -8,21 +8,48 @@ use core::panic;
 
 use crate::mm::alloc::alloc_pages;
 use crate::mm::alloc::free_pages;
+use crate::mm::alloc::LinkedList;
 use crate::mm::PageFrame;
-use crate::mm::PAGE_SHIFT;
+use crate::mm::{PAGE_SHIFT, PAGE_SIZE};
 
+use super::asid::KERNEL_ASID;
 use super::pte::{Flags, PTE};
+use super::tlb::{flush_tlb, flush_tlb_all};
 use super::{PhysicalAddr, VirtualAddr};
 
 const NPTE: usize = 512;
 
+/// Sv39 virtual address is 39 bits, higher bits are the same as bit 38
+const VA_BITS: usize = 39;
+
+/// Start of kernel half in 39 bits address
+const KERNEL_HALF: usize = 1 << (VA_BITS - 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Some page in range is already mapped
+    AlreadyMapped,
+    /// Some page in range is not mapped
+    NotMapped,
+    /// Can't alloc page for dir
+    NoMemory,
+    /// Address is not page aligned or out of range
+    BadRange,
+}
+
+/// Operation of `update_dir`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Update {
+    Unmap,
+    Protect(Flags),
+}
+
 #[repr(C, align(4096))]
 pub struct PageTable {
     entries: [PTE; NPTE],
 }
 
 const SATP_PPN_RANGE: Range<usize> = 0..44;
-#[allow(unused)]
 const SATP_ASID_RANGE: Range<usize> = 44..60;
 const SATP_MODE_RANGE: Range<usize> = 60..64;
 
@@ -43,18 +70,41 @@ impl PageTable {
         unsafe { Box::new_zeroed().assume_init() }
     }
 
+    /// alloc a new user pagetable, which shares kernel space with `KERNEL_PAGETABLE`
+    pub fn new_user() -> Box<Self> {
+        let mut pagetable = Self::new();
+        let kernel = unsafe { &super::KERNEL_PAGETABLE };
+        for idx in NPTE / 2..NPTE {
+            let mut pte = kernel.entries[idx];
+            if pte.is_valid() {
+                // mark global, so it will not be freed by user pagetable
+                pte.set_flags(pte.get_flags() | Flags::GLOBAL);
+            }
+            pagetable.entries[idx] = pte;
+        }
+        pagetable
+    }
+
     /// alloc a new dir
-    fn alloc_dir() -> PTE {
-        let page = alloc_pages(0).unwrap();
+    fn alloc_dir() -> Result<PTE, MapError> {
+        let page = alloc_pages(0).ok_or(MapError::NoMemory)?;
         unsafe {
             page.clear(0);
         }
-        PTE::new(Some(page), Flags::VALID)
+        Ok(PTE::new(Some(page), Flags::VALID))
     }
 
-    /// recursive free dir
+    /// alloc dir of `va` in root, user pagetables created later share it
+    pub fn alloc_root_dir(&mut self, va: VirtualAddr) -> Result<(), MapError> {
+        self.walk_alloc(va, 1).map(|_| ())
+    }
+
+    /// recursive free dir, dir shared with kernel (global) is skipped
     fn free_dir(dir: &mut [PTE; 512]) {
         for pte in dir {
+            if pte.get_flags().contains(Flags::GLOBAL) {
+                continue;
+            }
             if let Some(next_dir) = pte.next_level() {
                 PageTable::free_dir(next_dir);
 
@@ -64,43 +114,216 @@ impl PageTable {
         }
     }
 
-    /// walk to get the `PTE` of `pfn`, alloc if there is no dir
-    fn walk_alloc(&mut self, pfn: PageFrame) -> &mut PTE {
-        let ppn = pfn.page_numbers();
+    /// walk to get the `PTE` of `va` at `level`, alloc if there is no dir
+    fn walk_alloc(&mut self, va: VirtualAddr, level: usize) -> Result<&mut PTE, MapError> {
+        let ppn = va.virtual_page_frame().page_numbers();
 
         let mut cur = &mut self.entries;
-        for i in (1..3).rev() {
+        for i in ((level + 1)..3).rev() {
+            if cur[ppn[i]].is_leaf() {
+                return Err(MapError::AlreadyMapped);
+            }
             match cur[ppn[i]].next_level() {
                 Some(np) => {
                     cur = np;
                 }
                 None => {
-                    let new_pte = Self::alloc_dir();
+                    let new_pte = Self::alloc_dir()?;
                     cur[ppn[i]] = new_pte;
                     cur = new_pte.next_level().unwrap();
                 }
             }
         }
 
-        &mut cur[ppn[0]]
+        Ok(&mut cur[ppn[level]])
     }
 
-    /// map a physical address `pa` to pagetable's virtual address `va` with `flags`
-    pub fn map(&mut self, pa: PhysicalAddr, va: VirtualAddr, sz: usize, flags: Flags) {
-        let start = va.virtual_page_frame();
-        let end = (va + sz).virtual_page_frame_round_up();
+    /// split huge page `pte` at `level` to pages of next level with the same flags
+    fn split(pte: &mut PTE, level: usize) -> Result<(), MapError> {
+        let dir = Self::alloc_dir()?;
+        let ptes = dir.next_level().unwrap();
+        let mut ppf = pte.get_page_frame().unwrap();
+        let step = level_size(level - 1) >> PAGE_SHIFT;
+        for next in ptes.iter_mut() {
+            next.set_page_number(ppf).set_flags(pte.get_flags());
+            ppf = PageFrame::new(ppf.get_ppn() + step);
+        }
+        *pte = dir;
+        Ok(())
+    }
+
+    /// apply `op` on leaves in `start` ~ `end` of `dir` at `level`, which maps
+    /// from `base`. Addresses are 39 bits. Huge page partly in range is split
+    /// first, and dir emptied by unmap is added to `freed`.
+    ///
+    /// Return whether all pages in range are mapped.
+    fn update_dir(
+        dir: &mut [PTE; NPTE],
+        level: usize,
+        base: usize,
+        start: usize,
+        end: usize,
+        op: Update,
+        freed: &mut LinkedList,
+    ) -> Result<bool, MapError> {
+        let size = level_size(level);
+        let mut all_mapped = true;
+
+        let first = (start - base) / size;
+        let last = (end - 1 - base) / size;
+        for (idx, pte) in dir.iter_mut().enumerate().take(last + 1).skip(first) {
+            let lo = base + idx * size;
+            let (s, e) = (start.max(lo), end.min(lo + size));
+
+            if !pte.is_valid() {
+                all_mapped = false;
+                continue;
+            }
+            // dir shared with kernel
+            if pte.is_dir() && pte.get_flags().contains(Flags::GLOBAL) {
+                continue;
+            }
+            if pte.is_leaf() {
+                if s == lo && e == lo + size {
+                    match op {
+                        Update::Unmap => pte.clear(),
+                        Update::Protect(flags) => pte.set_flags(flags),
+                    };
+                    flush_tlb(sign_extend(lo));
+                    continue;
+                }
+                Self::split(pte, level)?;
+            }
 
-        let mut ppf = pa.page_frame();
+            let next = pte.next_level().unwrap();
+            all_mapped &= Self::update_dir(next, level - 1, lo, s, e, op, freed)?;
+            // root dirs of kernel half are shared by user pagetables
+            let shared = level == 2 && lo >= KERNEL_HALF;
+            if op == Update::Unmap && !shared && next.iter().all(|pte| !pte.is_valid()) {
+                let page: VirtualAddr = pte.get_page_frame().unwrap().into();
+                pte.clear();
+                // link is stored in the dir, which is an invalid pte as it is aligned
+                freed.push(page.as_ptr());
+            }
+        }
+        Ok(all_mapped)
+    }
 
-        for pfn in start..end {
-            let pte = self.walk_alloc(pfn);
-            if pte.is_valid() {
-                panic!("Remap");
+    fn update_range(&mut self, va: VirtualAddr, sz: usize, op: Update) -> Result<bool, MapError> {
+        let (start, end) = check_range(va, sz)?;
+        if start == end {
+            return Ok(true);
+        }
+        let mut freed = LinkedList::new();
+        let result = Self::update_dir(&mut self.entries, 2, 0, start, end, op, &mut freed);
+        if !freed.empty() {
+            // freed dirs may be cached by tlb, flush once for all of them
+            flush_tlb_all();
+            while let Some(page) = freed.pop() {
+                free_pages(VirtualAddr(page as usize).page_frame(), 0);
             }
-            pte.clear().set_page_number(ppf).set_flags(flags);
+        }
+        result
+    }
+
+    /// unmap pagetable's virtual address `va` ~ `va + sz`, page not mapped is
+    /// skipped, and dirs become empty are freed
+    pub fn unmap(&mut self, va: VirtualAddr, sz: usize) -> Result<(), MapError> {
+        self.update_range(va, sz, Update::Unmap).map(|_| ())
+    }
+
+    /// change flags of mapped pages in `va` ~ `va + sz` to `flags`, return
+    /// `NotMapped` if any page in range is not mapped, but mapped pages are
+    /// still changed
+    pub fn protect(&mut self, va: VirtualAddr, sz: usize, flags: Flags) -> Result<(), MapError> {
+        match self.update_range(va, sz, Update::Protect(flags))? {
+            true => Ok(()),
+            false => Err(MapError::NotMapped),
+        }
+    }
 
-            ppf = ppf.next();
+    /// translate virtual address `va` to physical address, return `None`
+    /// if not mapped
+    pub fn translate(&self, va: VirtualAddr) -> Option<(PhysicalAddr, Flags)> {
+        let ppn = va.virtual_page_frame().page_numbers();
+
+        let mut pte = &self.entries[ppn[2]];
+        let mut level = 2;
+        while let Some(dir) = pte.next_level().filter(|_| level > 0) {
+            level -= 1;
+            pte = &dir[ppn[level]];
         }
+        let pa: PhysicalAddr = pte.get_page_frame()?.into();
+        Some((pa + (va.0 & (level_size(level) - 1)), pte.get_flags()))
+    }
+
+    /// highest level of leaf which can map `pa` to `va` in `sz`
+    fn leaf_level(pa: PhysicalAddr, va: VirtualAddr, sz: usize) -> usize {
+        (0..3)
+            .rev()
+            .find(|&level| {
+                let size = level_size(level);
+                pa.0 & (size - 1) == 0 && va.0 & (size - 1) == 0 && sz >= size
+            })
+            .unwrap_or(0)
+    }
+
+    /// map a physical address `pa` to pagetable's virtual address `va` with `flags`,
+    /// use 2M or 1G huge page if both address are aligned.
+    ///
+    /// Nothing is mapped if failed.
+    pub fn map(
+        &mut self,
+        pa: PhysicalAddr,
+        va: VirtualAddr,
+        sz: usize,
+        flags: Flags,
+    ) -> Result<(), MapError> {
+        let (start, end) = check_range(va, sz)?;
+        if pa.0 & (PAGE_SIZE - 1) != 0 {
+            return Err(MapError::BadRange);
+        }
+
+        let mut cur_pa = pa;
+        let mut cur_va = va;
+        let end = va.0 + (end - start);
+        while cur_va.0 < end {
+            let level = Self::leaf_level(cur_pa, cur_va, end - cur_va.0);
+            if let Err(err) = self.map_one(cur_pa, cur_va, level, flags) {
+                self.unmap(va, cur_va - va)?;
+                return Err(err);
+            }
+
+            cur_pa += level_size(level);
+            cur_va += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// map a leaf at `level`, use smaller page if there is a dir already
+    fn map_one(
+        &mut self,
+        pa: PhysicalAddr,
+        va: VirtualAddr,
+        level: usize,
+        flags: Flags,
+    ) -> Result<(), MapError> {
+        let mut level = level;
+        let pte = loop {
+            let pte = self.walk_alloc(va, level)?;
+            if level > 0 && pte.is_dir() {
+                level -= 1;
+                continue;
+            }
+            break pte;
+        };
+        if pte.is_valid() {
+            return Err(MapError::AlreadyMapped);
+        }
+        pte.clear()
+            .set_page_number(pa.page_frame())
+            .set_flags(flags);
+        Ok(())
     }
 
     pub fn map_kernel(&mut self) {
@@ -116,39 +339,61 @@ impl PageTable {
         let data_start = VirtualAddr(data_start as usize);
         let bss_start = VirtualAddr(bss_start as usize);
         let kernel_end = VirtualAddr(kernel_end as usize);
-        let mem_end = unsafe { crate::mm::MEMORY_END };
-        let mem_end: VirtualAddr = mem_end.into();
-
         self.map(
             text_start.into(),
             text_start,
             rodata_start - text_start,
-            Flags::READABLE | Flags::EXECUTABLE,
-        );
+            Flags::READABLE | Flags::EXECUTABLE | Flags::GLOBAL,
+        )
+        .expect("Map kernel failed");
         self.map(
             rodata_start.into(),
             rodata_start,
             data_start - rodata_start,
-            Flags::READABLE,
-        );
+            Flags::READABLE | Flags::GLOBAL,
+        )
+        .expect("Map kernel failed");
         self.map(
             data_start.into(),
             data_start,
             bss_start - data_start,
-            Flags::READABLE | Flags::WRITABLE,
-        );
+            Flags::READABLE | Flags::WRITABLE | Flags::GLOBAL,
+        )
+        .expect("Map kernel failed");
         self.map(
             bss_start.into(),
             bss_start,
             kernel_end - bss_start,
-            Flags::READABLE | Flags::WRITABLE,
-        );
-        self.map(
-            kernel_end.into(),
-            kernel_end,
-            mem_end - kernel_end,
-            Flags::READABLE | Flags::WRITABLE,
-        );
+            Flags::READABLE | Flags::WRITABLE | Flags::GLOBAL,
+        )
+        .expect("Map kernel failed");
+
+        // Linear map of memory except kernel, memory removed from memblock,
+        // such as no-map reserved memory, is not mapped
+        let mut map_linear = |start: VirtualAddr, end: VirtualAddr| {
+            if start >= end {
+                return;
+            }
+            assert!(
+                end <= VirtualAddr(crate::mm::vmalloc::VMALLOC_START),
+                "Linear map overlaps vmalloc region"
+            );
+            self.map(
+                start.into(),
+                start,
+                end - start,
+                Flags::READABLE | Flags::WRITABLE | Flags::GLOBAL,
+            )
+            .expect("Map kernel failed");
+        };
+        unsafe {
+            crate::mm::memblock::MEM_BLOCK.for_each_present(|base, size| {
+                let start: VirtualAddr = base.into();
+                let end: VirtualAddr = (base + size).into();
+                map_linear(start, end.min(text_start));
+                map_linear(start.max(kernel_end), end);
+            });
+        }
     }
 
     /// get pagetable's physical address
@@ -156,25 +401,74 @@ impl PageTable {
         VirtualAddr(self.entries.as_ptr() as usize).into()
     }
 
-    /// get sv39 format of pagetable
-    pub fn as_sv39(&self) -> usize {
+    /// get sv39 format of pagetable with `asid`
+    pub fn as_sv39(&self, asid: usize) -> usize {
         let mut satp = 0;
         satp.set_bits(SATP_MODE_RANGE, 8);
+        satp.set_bits(SATP_ASID_RANGE, asid);
         satp.set_bits(SATP_PPN_RANGE, self.as_phys_addr().page_frame().get_ppn());
         satp
     }
 
-    pub(super) fn load(&self) {
-        let addr = self.as_sv39();
+    /// load pagetable as kernel and flush all tlb
+    pub fn load(&self) {
+        self.load_asid(KERNEL_ASID, true);
+    }
+
+    /// load pagetable with `asid`, flush all tlb if `flush_all`, otherwise
+    /// only flush the non-global entries of `asid`
+    pub fn load_asid(&self, asid: usize, flush_all: bool) {
+        let addr = self.as_sv39(asid);
         unsafe {
-            asm!("
-                csrw satp, {}
-                sfence.vma
-            ", in(reg) addr);
+            if flush_all {
+                asm!("
+                    csrw satp, {}
+                    sfence.vma
+                ", in(reg) addr);
+            } else {
+                asm!("
+                    csrw satp, {}
+                    sfence.vma zero, {}
+                ", in(reg) addr, in(reg) asid);
+            }
         }
     }
 }
 
+/// Check `va` is page aligned and `va` ~ `va + sz` is in one half of sv39,
+/// return the range in 39 bits, `sz` is rounded up to page size
+fn check_range(va: VirtualAddr, sz: usize) -> Result<(usize, usize), MapError> {
+    if va.0 & (PAGE_SIZE - 1) != 0 || sz > usize::MAX - PAGE_SIZE {
+        return Err(MapError::BadRange);
+    }
+    let sz = align_up!(sz, PAGE_SIZE);
+    let high = va.0 >> (VA_BITS - 1);
+    if high != 0 && high != usize::MAX >> (VA_BITS - 1) {
+        return Err(MapError::BadRange);
+    }
+
+    let start = va.0 & ((1 << VA_BITS) - 1);
+    let half_end = (start | ((1 << (VA_BITS - 1)) - 1)) + 1;
+    match start.checked_add(sz) {
+        Some(end) if end <= half_end => Ok((start, end)),
+        _ => Err(MapError::BadRange),
+    }
+}
+
+/// Extend 39 bits address to 64 bits
+fn sign_extend(addr: usize) -> VirtualAddr {
+    if addr & (1 << (VA_BITS - 1)) != 0 {
+        VirtualAddr(addr | !((1 << VA_BITS) - 1))
+    } else {
+        VirtualAddr(addr)
+    }
+}
+
+/// size of memory mapped by a leaf at `level`
+fn level_size(level: usize) -> usize {
+    1 << (PPN_SIZE * level + PAGE_SHIFT)
+}
+
 fn format_dir(
     formatter: &mut core::fmt::Formatter,
     ptes: &[PTE],
@@ -6,7 +6,7 @@ use bitflags::bitflags;
 use crate::mm::{page::PageFrame, VirtualAddr, PAGE_SHIFT};
 
 bitflags! {
-    pub struct Flags: u8 {
+    pub struct Flags: u16 {
         const VALID =       1 << VALID_BIT;
         const READABLE =    1 << 1;
         const WRITABLE =    1 << 2;
@@ -15,12 +15,14 @@ bitflags! {
         const GLOBAL =      1 << 5;
         const ACCESSED =    1 << 6;
         const DIRTY =       1 << 7;
+        /// Reserved for software, page is shared copy-on-write
+        const COW =         1 << 8;
     }
 }
 
 const VALID_BIT: usize = 0;
 
-const FLAG_RANGE: core::ops::Range<usize> = 0..8;
+const FLAG_RANGE: core::ops::Range<usize> = 0..10;
 const PAGE_NUMBER_RANGE: core::ops::Range<usize> = 10..54;
 
 #[repr(C)]
@@ -71,7 +73,7 @@ impl PTE {
     }
 
     pub fn get_flags(&self) -> Flags {
-        unsafe { Flags::from_bits_unchecked(self.0.get_bits(FLAG_RANGE) as u8) }
+        unsafe { Flags::from_bits_unchecked(self.0.get_bits(FLAG_RANGE) as u16) }
     }
 
     pub fn clear(&mut self) -> &mut Self {
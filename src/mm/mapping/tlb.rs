@@ -0,0 +1,55 @@
+//! TLB flush and cross-hart shootdown
+//!
+//! `PageTable` flushes TLB of current hart when it is changed. Other harts
+//! which have the pagetable active are flushed by SBI remote fence. A hart
+//! flushes the ASID of an address space when switching to it, so only harts
+//! running the address space need to be flushed.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::VirtualAddr;
+use crate::proc::hartid;
+use crate::sbi::{remote_sfence_vma, remote_sfence_vma_asid};
+
+/// Harts using `KERNEL_PAGETABLE`, that is all booted harts as kernel
+/// mappings are global
+static KERNEL_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// flush tlb of `va` in current hart
+pub fn flush_tlb(va: VirtualAddr) {
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) va.0);
+    }
+}
+
+/// flush all tlb in current hart
+pub fn flush_tlb_all() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+/// Flush tlb of `start` ~ `start + size` on harts in `harts` except current
+/// hart, which is flushed by `PageTable`. Only entries of `asid` are flushed
+/// if it is not `None`.
+pub fn flush_remote(harts: usize, start: VirtualAddr, size: usize, asid: Option<usize>) {
+    let remote = harts & !(1 << hartid());
+    if remote == 0 {
+        return;
+    }
+    match asid {
+        Some(asid) => remote_sfence_vma_asid(remote, start.0, size, asid),
+        None => remote_sfence_vma(remote, start.0, size),
+    }
+}
+
+/// Flush kernel mapping `start` ~ `start + size` on all other harts
+pub fn flush_kernel(start: VirtualAddr, size: usize) {
+    flush_remote(KERNEL_HARTS.load(Ordering::Acquire), start, size, None);
+}
+
+/// Track harts which may cache `KERNEL_PAGETABLE`
+pub(super) fn init() {
+    KERNEL_HARTS.fetch_or(1 << hartid(), Ordering::AcqRel);
+}
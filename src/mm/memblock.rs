@@ -7,12 +7,18 @@
 //!
 
 use core::fmt::Debug;
+use core::ops::Range;
 
 use super::{PhysicalAddr, VirtualAddr};
 
 pub struct MemBlock<const N: usize, const M: usize> {
+    /// Free memory, which does not overlap with `reserved`
     memory: MemBlockType<N>,
     reserved: MemBlockType<M>,
+    /// All memory not removed, including reserved
+    present: MemBlockType<N>,
+    /// Memory allowed to alloc
+    alloc_range: Range<PhysicalAddr>,
 }
 
 struct MemBlockType<const N: usize> {
@@ -21,14 +27,9 @@ struct MemBlockType<const N: usize> {
 }
 
 impl<const N: usize> MemBlockType<N> {
-    pub fn search(&self, base: PhysicalAddr) -> Result<usize, usize> {
-        self.region[0..self.len].binary_search_by(|m| m.base.cmp(&base))
-    }
-
-    /// Insert to pos and do not combine.
     fn insert_no_combine(&mut self, region: &MemBlockRegion, pos: usize) {
-        if pos > self.len {
-            panic!("Invalid pos: insert in {} but len is {}", pos, self.len);
+        if self.len == N {
+            panic!("Too many memblock regions");
         }
 
         self.region
@@ -38,135 +39,69 @@ impl<const N: usize> MemBlockType<N> {
         self.len += 1;
     }
 
-    fn delete(&mut self, pos: usize) {
-        if pos > self.len {
-            panic!("Invalid pos: delete in {} but len is {}", pos, self.len);
-        }
-
+    /// Delete regions in `range`
+    fn delete(&mut self, range: Range<usize>) {
         self.region
             .as_mut_slice()
-            .copy_within(pos + 1..self.len, pos);
-        self.len -= 1;
-        println!("{:x?}", &self.region[0..self.len]);
+            .copy_within(range.end..self.len, range.start);
+        self.len -= range.len();
     }
 
-    /// Insert to pos and combine contiguous region.
-    pub fn insert(&mut self, region: &MemBlockRegion, pos: usize) {
-        if pos != 0 {
-            let m = self.get(pos - 1);
-            if m.base + m.size == region.base {
-                // combine with prev
-                self.attach(pos - 1, region.size);
-
-                let m = self.get(pos - 1);
-                if pos < self.len {
-                    let n = self.get(pos);
-                    if m.base + m.size == n.base {
-                        // combine with next
-                        // m.size += n.size;
-                        let sz = n.size;
-                        self.attach(pos - 1, sz);
-                        self.delete(pos);
-                    }
-                }
-                println!("{:x?}", &self.region[0..self.len]);
-                return;
-            }
-        }
-
-        if pos < self.len {
-            let m = self.get(pos);
-            if m.base == region.base + region.size {
-                // combine with next
-                self.attach_before(pos, region.size);
-                println!("{:x?}", &self.region[0..self.len]);
-                return;
-            }
-        }
-
-        self.insert_no_combine(region, pos);
-        println!("{:x?}", &self.region[0..self.len]);
-    }
-
-    /// Check if this mem region exist or overlap
-    /// Return `Ok(pos)` if not exist or overlap, and can insert to `pos`.
-    /// Return `Err(pos)` if exist or overlap, conflict in `pos`.
-    pub fn check(&self, region: &MemBlockRegion) -> Result<usize, usize> {
-        let target = self.search(region.base);
-        match target {
-            Ok(pos) => Err(pos),
-            Err(pos) => {
-                // check prev
-                if pos != 0 {
-                    let m = self.get(pos - 1);
-                    if m.base + m.size > region.base {
-                        return Err(pos - 1);
-                    }
-                }
-
-                // check next
-                if pos < self.len {
-                    let m = self.get(pos);
-                    if m.base < region.base + region.size {
-                        return Err(pos);
-                    }
-                }
-
-                Ok(pos)
-            }
-        }
+    /// Regions which overlap with `base..end`, or also adjacent to it if
+    /// `adjacent`
+    fn overlapped(&self, base: PhysicalAddr, end: PhysicalAddr, adjacent: bool) -> Range<usize> {
+        let regions = self.as_slice();
+        let first = regions.partition_point(|m| m.end() < base || !adjacent && m.end() == base);
+        let last = regions.partition_point(|m| m.base < end || adjacent && m.base == end);
+        first..last.max(first)
     }
 
-    fn attach(&mut self, pos: usize, size: usize) {
-        if pos >= self.len {
-            panic!("Out of index");
-        }
-
-        self.region[pos].size += size;
-    }
-
-    fn attach_before(&mut self, pos: usize, size: usize) {
-        if pos >= self.len {
-            panic!("Out of index");
-        }
-
-        self.region[pos].base -= size;
-        self.region[pos].size += size;
-    }
-
-    fn detach_tail(&mut self, pos: usize, size: usize) {
-        if pos >= self.len {
-            panic!("Out of index");
-        }
-
-        if size > self.region[pos].size {
-            panic!("Too big");
-        }
-
-        if size == self.region[pos].size {
-            self.delete(pos);
-        } else {
-            self.region[pos].size -= size;
-        }
+    /// Add range, and combine it with overlapped and contiguous regions
+    pub fn add_range(&mut self, base: PhysicalAddr, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let mut region = MemBlockRegion { base, size };
+        let range = self.overlapped(base, base + size, true);
+        if !range.is_empty() {
+            let base = base.min(self.get(range.start).base);
+            let end = region.end().max(self.get(range.end - 1).end());
+            region = MemBlockRegion {
+                base,
+                size: end - base,
+            };
+        }
+        let pos = range.start;
+        self.delete(range);
+        self.insert_no_combine(&region, pos);
     }
 
-    fn detach_head(&mut self, pos: usize, size: usize) {
-        if pos >= self.len {
-            panic!("Out of index");
-        }
-
-        if size > self.region[pos].size {
-            panic!("Too big");
-        }
-
-        if size == self.region[pos].size {
-            self.delete(pos);
-        } else {
-            self.region[pos].size -= size;
-            self.region[pos].base += size;
-        }
-
-        println!("{:x?}", &self.region[0..self.len]);
+    /// Remove range, regions partly overlapped are split.
+    /// Return whether any region is overlapped.
+    pub fn remove_range(&mut self, base: PhysicalAddr, size: usize) -> bool {
+        if size == 0 {
+            return false;
+        }
+        let end = base + size;
+        let range = self.overlapped(base, end, false);
+        if range.is_empty() {
+            return false;
+        }
+        // Only the first and last regions may be partly overlapped
+        let first = *self.get(range.start);
+        let last = *self.get(range.end - 1);
+        let mut pos = range.start;
+        self.delete(range);
+        if first.base < base {
+            let size = base - first.base;
+            self.insert_no_combine(&MemBlockRegion { size, ..first }, pos);
+            pos += 1;
+        }
+        if last.end() > end {
+            let size = last.end() - end;
+            self.insert_no_combine(&MemBlockRegion { base: end, size }, pos);
+        }
+        true
     }
 
     pub fn get(&self, pos: usize) -> &MemBlockRegion {
@@ -174,7 +109,7 @@ impl<const N: usize> MemBlockType<N> {
             panic!("Out of index");
         }
 
-        unsafe { &self.region.get_unchecked(pos) }
+        unsafe { self.region.get_unchecked(pos) }
     }
 
     pub fn as_slice(&self) -> &[MemBlockRegion] {
@@ -188,94 +123,76 @@ struct MemBlockRegion {
     size: usize,
 }
 
+impl MemBlockRegion {
+    fn end(&self) -> PhysicalAddr {
+        self.base + self.size
+    }
+}
+
 impl<const N: usize, const M: usize> MemBlock<N, M> {
-    /// Add block to memory region.
-    /// Not support overlap now.
+    /// Add block to memory region, reserved part of it is not added.
     pub fn add(&mut self, base: PhysicalAddr, size: usize) {
-        let region = MemBlockRegion { base, size };
-        if self.reserved.check(&region).is_err() {
-            panic!("Overlap with reserved");
-        }
-
-        match self.memory.check(&region) {
-            Ok(pos) => self.memory.insert(&region, pos),
-            Err(_) => panic!("Overlap with memory"),
+        self.present.add_range(base, size);
+        self.memory.add_range(base, size);
+        for r in self.reserved.as_slice() {
+            self.memory.remove_range(r.base, r.size);
         }
     }
 
-    /// Add block to reserved region.
-    /// Only support overlap in one `memory` region.
-    /// Not support overlap in `reserved` now.
+    /// Add block to reserved region, and remove it from memory region.
     pub fn reserve(&mut self, base: PhysicalAddr, size: usize) {
-        let region = MemBlockRegion { base, size };
-        let mut found = false;
-        match self.memory.search(base) {
-            Ok(pos) => {
-                let m = self.memory.get(pos);
-                if m.size < region.size {
-                    panic!("Unsupported now");
-                }
-                self.memory.detach_head(pos, region.size);
-                found = true;
-            }
-            Err(pos) => {
-                // Split prev
-                if pos != 0 {
-                    let m = self.memory.get(pos - 1);
-                    if m.base + m.size > region.base {
-                        // overlap
-                        if region.base + region.size > m.base + m.size {
-                            panic!("Unsupported now");
-                        }
-                        if region.base + region.size == m.base + m.size {
-                            self.memory.detach_tail(pos - 1, region.size);
-                        } else {
-                            let end = m.base + m.size;
-                            self.memory.detach_tail(pos - 1, end - region.base);
+        if !self.memory.remove_range(base, size) {
+            println!("Warning: reserve region {:?} not in memory", base);
+        }
+        self.reserved.add_range(base, size);
+    }
 
-                            let base = region.base + region.size;
-                            let size = end - base;
-                            self.memory
-                                .insert_no_combine(&MemBlockRegion { base, size }, pos);
-                        }
-                        found = true;
-                    }
-                }
+    /// Remove block from both memory and reserved region, as if it does not
+    /// exist.
+    pub fn remove(&mut self, base: PhysicalAddr, size: usize) {
+        self.present.remove_range(base, size);
+        self.memory.remove_range(base, size);
+        self.reserved.remove_range(base, size);
+    }
 
-                // Split next
-                if pos < self.memory.len {
-                    let m = self.memory.get(pos);
-                    if m.base < region.base + region.size {
-                        panic!("Unsupported now");
-                    }
-                }
-            }
+    /// Call `f` with each range of memory not removed
+    pub fn for_each_present(&self, mut f: impl FnMut(PhysicalAddr, usize)) {
+        for mem in self.present.as_slice() {
+            f(mem.base, mem.size);
         }
+    }
 
-        match self.reserved.check(&region) {
-            Ok(pos) => {
-                if !found {
-                    println!("Warning: reserve region not in memory");
-                }
-                self.reserved.insert(&region, pos)
-            }
-            Err(_) => panic!("Overlap with reserved"),
-        }
+    /// Only memory in `base..end` is allocated, for memory not mapped yet
+    pub fn set_alloc_range(&mut self, base: PhysicalAddr, end: PhysicalAddr) {
+        self.alloc_range = base..end;
     }
 
-    /// alloc one
-    pub fn alloc(&mut self, size: usize) -> VirtualAddr {
-        match self
-            .memory
+    /// Base of the memory region containing `addr`
+    pub fn memory_containing(&self, addr: PhysicalAddr) -> Option<PhysicalAddr> {
+        self.memory
             .as_slice()
             .iter()
-            .position(|mem| mem.size >= size)
-        {
-            Some(pos) => {
-                let mem = self.memory.get(pos);
-                let addr = mem.base;
-                self.memory.detach_head(pos, size);
-                addr.into()
+            .find(|mem| mem.base <= addr && addr < mem.end())
+            .map(|mem| mem.base)
+    }
+
+    /// Alloc `size` aligned to `align` from top of memory, and reserve it.
+    ///
+    /// # Panic
+    /// Panic if no enough memory.
+    pub fn alloc(&mut self, size: usize, align: usize) -> VirtualAddr {
+        assert!(align.is_power_of_two());
+        let Range { start, end } = self.alloc_range;
+        let base = self.memory.as_slice().iter().rev().find_map(|mem| {
+            let low: usize = start.max(mem.base).into();
+            let high: usize = end.min(mem.end()).into();
+            let base = high.checked_sub(size)? & !(align - 1);
+            Some(PhysicalAddr::new(base)).filter(|_| base >= low)
+        });
+        match base {
+            Some(base) => {
+                self.reserve(base, size);
+                base.into()
             }
             None => panic!("No enough memory"),
         }
@@ -307,4 +224,12 @@ pub static mut MEM_BLOCK: MemBlock<128, 128> = MemBlock {
             size: 0,
         }; 128],
     },
+    present: MemBlockType {
+        len: 0,
+        region: [MemBlockRegion {
+            base: PhysicalAddr::new(0),
+            size: 0,
+        }; 128],
+    },
+    alloc_range: PhysicalAddr::new(0)..PhysicalAddr::new(usize::MAX),
 };
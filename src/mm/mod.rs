@@ -3,11 +3,16 @@
 use core::fmt::{Debug, Error, Formatter};
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 
+pub mod addr_space;
 pub mod alloc;
+pub mod kstack;
 #[allow(dead_code)]
 pub mod mapping;
 pub mod memblock;
 pub mod page;
+pub mod vma;
+#[cfg_attr(not(feature = "vmalloc-heap"), allow(dead_code))]
+pub mod vmalloc;
 
 pub use page::*;
 
@@ -19,11 +24,9 @@ pub use page::*;
 #[cfg(target_pointer_width = "64")]
 const PAGE_OFF: usize = 0xffffffc0_00000000;
 
-const MEMORY_OFFSET: usize = 0x8000_0000;
-
-const PAGE_SHIFT: usize = 12;
-const PAGE_MASK: usize = (1 << PAGE_SHIFT) - 1;
-const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_MASK: usize = (1 << PAGE_SHIFT) - 1;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -195,38 +198,89 @@ extern "C" {
     fn boot_page_table();
 }
 
-static mut MEMORY_END: PhysicalAddr = PhysicalAddr(0);
+/// Call `f` with each memory range in dtb, memory above `MAX_PHYS_ADDR` is
+/// not supported and skipped
+fn for_each_memory(mut f: impl FnMut(PhysicalAddr, usize)) {
+    crate::dtb::for_each_memory(|mem, len| {
+        let len = len.min(MAX_PHYS_ADDR.saturating_sub(mem.into()));
+        if len != 0 {
+            f(mem, len);
+        }
+    });
+}
 
 pub fn init_early() {
-    let (mem, len) = crate::dtb::get_memory();
-    unsafe {
-        MEMORY_END = mem + len;
+    extern "C" {
+        fn text_start();
     }
-    println!("Memory {:?} len {:#x} npage {}", mem, len, len >> 12);
-    println!("Kernel end {:#x}", kernel_end as usize - PAGE_OFF);
+    let kernel_start = PhysicalAddr::from(VirtualAddr::new(text_start as usize));
+    let kernel_end = PhysicalAddr::from(VirtualAddr::new(kernel_end as usize));
+
+    // Init memblock
+    let mut nr_memory = 0;
+    for_each_memory(|mem, len| unsafe {
+        println!(
+            "Memory {:?} len {:#x} npage {}",
+            mem,
+            len,
+            len >> PAGE_SHIFT
+        );
+        memblock::MEM_BLOCK.add(mem, len);
+        nr_memory += 1;
+    });
+    assert!(nr_memory > 0, "No memory in dtb");
+    println!("Kernel {:?} - {:?}", kernel_start, kernel_end);
 
     let pt = boot_page_table as usize as *const mapping::pagetable::PageTable;
     let pt = unsafe { &*pt };
     println!("{:#?}", pt);
 
-    // Init memblock
+    crate::dtb::for_each_reserved(|base, size, no_map| unsafe {
+        println!("Reserved {:?} len {:#x} no-map {}", base, size, no_map);
+        if no_map {
+            memblock::MEM_BLOCK.remove(base, size);
+        } else {
+            memblock::MEM_BLOCK.reserve(base, size);
+        }
+    });
     unsafe {
-        memblock::MEM_BLOCK.add(mem, len);
-        memblock::MEM_BLOCK.reserve(
-            PhysicalAddr::new(MEMORY_OFFSET),
-            kernel_end as usize - PAGE_OFF - MEMORY_OFFSET,
-        );
+        // Firmware is loaded below kernel and may be not reserved in dtb,
+        // so free memory below kernel is reserved too
+        let mem = memblock::MEM_BLOCK
+            .memory_containing(kernel_start)
+            .expect("Kernel not in memory");
+        memblock::MEM_BLOCK.reserve(mem, kernel_end - mem);
+        let (dtb, len) = crate::dtb::blob();
+        memblock::MEM_BLOCK.reserve(dtb, len);
     }
 
-    // Init pages
-    Pages::init(len >> PAGE_SHIFT);
+    // Only the 1G containing kernel is mapped by boot page table
+    let boot_mapped = PhysicalAddr::new(align_down!(usize::from(kernel_start), 1 << 30));
+    unsafe {
+        memblock::MEM_BLOCK.set_alloc_range(boot_mapped, boot_mapped + (1 << 30));
+    }
+
+    // Init pages of each memory range
+    for_each_memory(|mem, len| {
+        Pages::init(mem.page_frame(), (mem + len).next_page_frame());
+    });
 
     // Free all free memory to buddy system
     unsafe {
         memblock::MEM_BLOCK.free_all(alloc::free_to_buddy);
     }
+    #[cfg(feature = "mm-debug")]
+    for zone_type in alloc::ZoneType::ALL {
+        println!(
+            "Zone {} free blocks {:?}",
+            zone_type.name(),
+            alloc::nr_free_blocks(zone_type)
+        );
+    }
 
     mapping::init_early();
+    kstack::init_early();
+    vmalloc::init_early();
 }
 
 pub(super) fn init() {
@@ -1,14 +1,28 @@
 //! Struct for per page
+//!
+//! Physical memory is split to sections, and `Page` arrays are only
+//! allocated for sections which have memory, so memory may start anywhere
+//! and have holes.
 
 use core::iter::Step;
 use core::mem::{size_of, MaybeUninit};
-use core::sync::atomic::AtomicU16;
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
 
 use crate::sync::Spin;
 
 use super::*;
 
-const PAGE_FRAME_OFFSET: usize = MEMORY_OFFSET >> PAGE_SHIFT;
+/// Physical memory above is not supported, as its linear map would reach
+/// vmalloc region
+pub const MAX_PHYS_ADDR: usize = super::vmalloc::VMALLOC_START - PAGE_OFF;
+
+/// A section is 128M
+const SECTION_PAGE_SHIFT: usize = 27 - PAGE_SHIFT;
+const PAGES_PER_SECTION: usize = 1 << SECTION_PAGE_SHIFT;
+const NR_SECTIONS: usize = MAX_PHYS_ADDR >> PAGE_SHIFT >> SECTION_PAGE_SHIFT;
+
+/// `Page` array of each section, null if the section has no memory
+static mut SECTIONS: [*mut Page; NR_SECTIONS] = [core::ptr::null_mut(); NR_SECTIONS];
 
 #[repr(transparent)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
@@ -20,25 +34,50 @@ impl PageFrame {
     }
 
     pub unsafe fn get_page(&self) -> &'static mut Page {
-        let idx = self.0 - PAGE_FRAME_OFFSET;
-        let pages = PAGES.assume_init_mut();
-        pages.get_mut(idx).unwrap()
+        self.get_page_checked()
+            .unwrap_or_else(|| panic!("No page of {:?}", self))
     }
 
     pub unsafe fn set_head_page(&self, npages: usize) {
-        let pages = PAGES.assume_init_mut();
-        let idx = self.0 - PAGE_FRAME_OFFSET;
-        for n in idx..(idx + npages) {
-            let page = pages.get_mut(n).unwrap();
-            page.head_page = self.0;
+        for frame in *self..PageFrame(self.0 + npages) {
+            frame.get_page().head_page = self.0;
         }
     }
 
+    /// Get page of frame, return `None` if it is out of memory
+    pub unsafe fn get_page_checked(&self) -> Option<&'static mut Page> {
+        let section = *SECTIONS.get(self.0 >> SECTION_PAGE_SHIFT)?;
+        if section.is_null() {
+            return None;
+        }
+        Some(&mut *section.add(self.0 & (PAGES_PER_SECTION - 1)))
+    }
+
     pub unsafe fn get_head_page(&self) -> PageFrame {
-        let pages = PAGES.assume_init_mut();
-        let page = pages.get_mut(self.0 - PAGE_FRAME_OFFSET).unwrap();
-        let pfn = page.head_page;
-        PageFrame(pfn)
+        PageFrame(self.get_page().head_page)
+    }
+
+    /// Reference count of page, it is 1 when allocated
+    pub fn ref_count(&self) -> usize {
+        unsafe { self.get_page().ref_count.load(Ordering::Acquire) }
+    }
+
+    pub unsafe fn set_ref_count(&self, count: usize) {
+        self.get_page().ref_count.store(count, Ordering::Release);
+    }
+
+    /// Increase reference count of page
+    pub fn get_ref(&self) {
+        unsafe {
+            self.get_page().ref_count.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Decrease reference count of page, return true if it drops to zero
+    pub fn put_ref(&self) -> bool {
+        let old = unsafe { self.get_page().ref_count.fetch_sub(1, Ordering::AcqRel) };
+        assert!(old > 0, "Put page {:#x} with zero ref count", self.0);
+        old == 1
     }
 
     pub fn get_ppn(&self) -> usize {
@@ -60,9 +99,15 @@ impl PageFrame {
     }
 }
 
-impl Into<VirtualAddr> for PageFrame {
-    fn into(self) -> VirtualAddr {
-        VirtualAddr::new(self.va())
+impl From<PageFrame> for PhysicalAddr {
+    fn from(frame: PageFrame) -> Self {
+        PhysicalAddr::new(frame.0 << PAGE_SHIFT)
+    }
+}
+
+impl From<PageFrame> for VirtualAddr {
+    fn from(frame: PageFrame) -> Self {
+        VirtualAddr::new(frame.va())
     }
 }
 
@@ -99,30 +144,75 @@ pub struct Page {
     // pobjs: usize, // approximate count
     /* slub */
     pub inuse: AtomicU16, // inuse objs
+    section: u16,         // section of page, to get its frame
+    pub frozen: bool,     // cpu slub of a hart, protected by lock of slub node
     pub slub_data: SlubData,
+    pub ref_count: AtomicUsize, // users of page
+    /* buddy, protected by lock of buddy system */
+    pub order: u8,  // order of block if it is free
+    pub free: bool, // head of a free block, linked by `list_node`
 }
 
 impl Page {
+    const fn new() -> Self {
+        Self {
+            list_node: Spin::new(alloc::DoubleLinkedList::new()),
+            head_page: 0,
+            slub: core::ptr::null_mut(),
+            freelist: Spin::new(alloc::LinkedList::new()),
+            inuse: AtomicU16::new(0),
+            section: 0,
+            frozen: false,
+            slub_data: SlubData { objs: 0 },
+            ref_count: AtomicUsize::new(0),
+            order: 0,
+            free: false,
+        }
+    }
+
+    /// Get page from its `list_node`
+    pub unsafe fn from_list_node(ln: *mut alloc::DoubleLinkedList) -> *mut Page {
+        let val: MaybeUninit<Page> = MaybeUninit::uninit();
+        let p = val.as_ptr();
+        let l = (*p).list_node.get();
+        let offset = l as usize - p as usize;
+
+        (ln as usize - offset) as *mut Page
+    }
+
     pub fn get_frame(&self) -> PageFrame {
+        let section = self.section as usize;
         unsafe {
-            let pages = PAGES.assume_init_mut().as_ptr();
-            let pfn = (self as *const Page).offset_from(pages);
-            PageFrame(pfn as usize)
+            let idx = (self as *const Page).offset_from(SECTIONS[section]);
+            PageFrame((section << SECTION_PAGE_SHIFT) + idx as usize)
         }
     }
 }
 
-static mut PAGES: MaybeUninit<&'static mut [Page]> = MaybeUninit::uninit();
-
 pub struct Pages {}
 
 impl Pages {
-    pub fn init(num_page: usize) {
-        let size = num_page * size_of::<Page>();
-        unsafe {
-            let addr: usize = super::memblock::MEM_BLOCK.alloc(size).into();
-            println!("Pages addr {:#x?}", addr);
-            PAGES.write(core::slice::from_raw_parts_mut(addr as *mut Page, num_page));
+    /// Alloc pages of sections which have frames from `start` to `end`,
+    /// `end` is exclusive
+    pub fn init(start: PageFrame, end: PageFrame) {
+        let first = start.0 >> SECTION_PAGE_SHIFT;
+        let last = (end.0 - 1) >> SECTION_PAGE_SHIFT;
+        let size = align_up!(PAGES_PER_SECTION * size_of::<Page>(), PAGE_SIZE);
+        let sections = unsafe { SECTIONS[first..=last].iter_mut() };
+        for (section, ptr) in (first..).zip(sections) {
+            if !ptr.is_null() {
+                continue;
+            }
+            unsafe {
+                let addr: usize = super::memblock::MEM_BLOCK.alloc(size, PAGE_SIZE).into();
+                println!("Pages of section {} addr {:#x?}", section, addr);
+                let pages = core::slice::from_raw_parts_mut(addr as *mut Page, PAGES_PER_SECTION);
+                for page in pages.iter_mut() {
+                    (page as *mut Page).write(Page::new());
+                    page.section = section as u16;
+                }
+                *ptr = pages.as_mut_ptr();
+            }
         }
     }
 }
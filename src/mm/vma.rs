@@ -0,0 +1,134 @@
+//! Virtual memory area
+//!
+//! A VMA is a range of user address space with the same permission. Pages in
+//! a VMA are allocated on first touch by the page fault handler.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::mapping::Flags;
+
+/// Memory access which causes a page fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn flag(self) -> Flags {
+        match self {
+            Access::Read => Flags::READABLE,
+            Access::Write => Flags::WRITABLE,
+            Access::Execute => Flags::EXECUTABLE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// Flags of pages in this area
+    pub flags: Flags,
+}
+
+impl Vma {
+    pub fn new(start: usize, end: usize, flags: Flags) -> Self {
+        Self { start, end, flags }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        self.flags.contains(access.flag())
+    }
+}
+
+/// VMAs of an address space, never overlap
+#[derive(Clone)]
+pub struct VmaList {
+    /// Indexed by start address
+    vmas: BTreeMap<usize, Vma>,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self {
+            vmas: BTreeMap::new(),
+        }
+    }
+
+    /// Find the VMA contains `va`
+    pub fn find(&self, va: usize) -> Option<&Vma> {
+        self.vmas
+            .range(..=va)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| va < vma.end)
+    }
+
+    /// Whether any VMA overlaps with `start` ~ `end`
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.vmas
+            .range(..end)
+            .next_back()
+            .map_or(false, |(_, vma)| vma.end > start)
+    }
+
+    /// Insert `vma` which must not overlap with others, merge it with
+    /// adjacent VMAs of same flags
+    pub fn insert(&mut self, vma: Vma) {
+        assert!(!self.overlaps(vma.start, vma.end), "VMA overlaps");
+        let mut vma = vma;
+
+        let prev = self.vmas.range(..vma.start).next_back().map(|(_, v)| *v);
+        if let Some(prev) = prev.filter(|v| v.end == vma.start && v.flags == vma.flags) {
+            self.vmas.remove(&prev.start);
+            vma.start = prev.start;
+        }
+        let next = self.vmas.get(&vma.end).copied();
+        if let Some(next) = next.filter(|v| v.flags == vma.flags) {
+            self.vmas.remove(&next.start);
+            vma.end = next.end;
+        }
+        self.vmas.insert(vma.start, vma);
+    }
+
+    /// Remove range `start` ~ `end` from all VMAs, VMA partly in range is split
+    pub fn remove(&mut self, start: usize, end: usize) {
+        let overlapped: Vec<Vma> = self
+            .vmas
+            .range(..end)
+            .rev()
+            .map(|(_, vma)| *vma)
+            .take_while(|vma| vma.end > start)
+            .collect();
+
+        for vma in overlapped {
+            self.vmas.remove(&vma.start);
+            if vma.start < start {
+                self.vmas
+                    .insert(vma.start, Vma::new(vma.start, start, vma.flags));
+            }
+            if vma.end > end {
+                self.vmas.insert(end, Vma::new(end, vma.end, vma.flags));
+            }
+        }
+    }
+
+    /// Find a free range of `size` in `low` ~ `high` from top to bottom,
+    /// return its start address
+    pub fn find_free(&self, size: usize, low: usize, high: usize) -> Option<usize> {
+        let mut end = high;
+        for vma in self.vmas.values().rev() {
+            if vma.start >= end {
+                continue;
+            }
+            if vma.end <= end && end - vma.end >= size {
+                break;
+            }
+            end = vma.start;
+        }
+        end.checked_sub(size).filter(|&start| start >= low)
+    }
+}
@@ -0,0 +1,148 @@
+//! Virtually contiguous kernel allocation
+//!
+//! `vmalloc` maps pages which may be not contiguous in physical memory to a
+//! contiguous range of the vmalloc region, so large allocations are not
+//! limited by the max order of buddy system, and need no power of two pages.
+//! Each area is followed by an unmapped guard page.
+
+use alloc::collections::BTreeMap;
+
+use super::alloc::{alloc_pages, free_pages};
+use super::mapping::tlb::flush_kernel;
+use super::mapping::{Flags, KERNEL_PAGETABLE};
+use super::{VirtualAddr, PAGE_SIZE};
+use crate::sync::Spin;
+
+/// Vmalloc region is the 8G below kernel stack region
+pub(super) const VMALLOC_START: usize = 0xffff_fffd_c000_0000;
+const VMALLOC_END: usize = 0xffff_ffff_c000_0000;
+
+/// Size of a root dir in sv39
+const ROOT_DIR_SIZE: usize = 1 << 30;
+
+struct VmallocSpace {
+    /// Size of allocated areas indexed by start address, created on first use
+    areas: Option<BTreeMap<usize, usize>>,
+}
+
+impl VmallocSpace {
+    /// Find a free range of `size` with a guard page after it
+    fn find_free(&self, size: usize) -> Option<usize> {
+        let mut start = VMALLOC_START;
+        if let Some(areas) = self.areas.as_ref() {
+            for (&area, &area_size) in areas {
+                if area - start >= size + PAGE_SIZE {
+                    break;
+                }
+                start = area + area_size + PAGE_SIZE;
+            }
+        }
+        Some(start).filter(|&start| VMALLOC_END - start >= size + PAGE_SIZE)
+    }
+}
+
+/// Allocated areas of vmalloc region
+static VMALLOC: Spin<VmallocSpace> = Spin::new(VmallocSpace { areas: None });
+
+/// Protects vmalloc region of `KERNEL_PAGETABLE`, areas are reserved in
+/// `VMALLOC` before they are mapped
+static PAGETABLE: Spin<()> = Spin::new(());
+
+/// Whether `va` is in vmalloc region
+pub fn is_vmalloc(va: VirtualAddr) -> bool {
+    let va: usize = va.into();
+    (VMALLOC_START..VMALLOC_END).contains(&va)
+}
+
+/// Unmap `size` from `start` of vmalloc region and free the pages
+fn unmap_area(start: VirtualAddr, size: usize) {
+    let _guard = PAGETABLE.lock();
+    let pagetable = unsafe { &mut KERNEL_PAGETABLE };
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        if let Some((pa, _)) = pagetable.translate(start + offset) {
+            free_pages(pa.page_frame(), 0);
+        }
+    }
+    pagetable.unmap(start, size).expect("Bad vmalloc area");
+    // area may be cached by other harts
+    flush_kernel(start, size);
+}
+
+/// Alloc a page and map it at `va`, return whether succeeded
+fn map_page(va: VirtualAddr) -> bool {
+    let frame = match alloc_pages(0) {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let _guard = PAGETABLE.lock();
+    let pagetable = unsafe { &mut KERNEL_PAGETABLE };
+    let flags = Flags::READABLE | Flags::WRITABLE | Flags::GLOBAL;
+    let ok = pagetable.map(frame.into(), va, PAGE_SIZE, flags).is_ok();
+    if !ok {
+        free_pages(frame, 0);
+    }
+    ok
+}
+
+/// Alloc `size` bytes of virtually contiguous memory, rounded up to pages.
+/// Return `None` if out of memory or vmalloc region.
+pub fn vmalloc(size: usize) -> Option<VirtualAddr> {
+    if size == 0 {
+        return None;
+    }
+    let size = align_up!(size, PAGE_SIZE);
+
+    // reserve the range, pages are allocated and mapped without the lock
+    let start = {
+        let mut space = VMALLOC.lock();
+        let start = space.find_free(size)?;
+        space
+            .areas
+            .get_or_insert_with(BTreeMap::new)
+            .insert(start, size);
+        VirtualAddr::new(start)
+    };
+    for offset in (0..size).step_by(PAGE_SIZE) {
+        if !map_page(start + offset) {
+            unmap_area(start, offset);
+            release_area(start);
+            return None;
+        }
+    }
+    Some(start)
+}
+
+/// Remove area at `va` from `VMALLOC`, so its range can be reused
+fn release_area(va: VirtualAddr) {
+    if let Some(areas) = VMALLOC.lock().areas.as_mut() {
+        areas.remove(&va.into());
+    }
+}
+
+/// Free area allocated by `vmalloc`
+///
+/// # Panic
+/// Panic if `va` is not the start of an area
+pub fn vfree(va: VirtualAddr) {
+    let size = VMALLOC
+        .lock()
+        .areas
+        .as_ref()
+        .and_then(|areas| areas.get(&va.into()).copied())
+        .unwrap_or_else(|| panic!("vfree bad address {:?}", va));
+    // area is released after unmapped, so it is not reused before
+    unmap_area(va, size);
+    release_area(va);
+}
+
+/// Alloc root dirs of vmalloc region before any user pagetable is created,
+/// so that areas are mapped in all pagetables
+pub(super) fn init_early() {
+    for va in (VMALLOC_START..VMALLOC_END).step_by(ROOT_DIR_SIZE) {
+        unsafe {
+            KERNEL_PAGETABLE
+                .alloc_root_dir(VirtualAddr::new(va))
+                .expect("No memory for vmalloc region");
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! ELF loader for user program
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::{current, enter_user};
+use crate::arch;
+use crate::elf::{Elf, ElfError, ProgramHeader, PF_R, PF_W, PF_X, PT_INTERP, PT_LOAD, PT_PHDR};
+use crate::mm::addr_space::{AddressSpace, USER_STACK_SIZE, USER_STACK_TOP};
+use crate::mm::mapping::Flags;
+use crate::mm::{VirtualAddr, PAGE_SHIFT, PAGE_SIZE};
+
+/// Load bias of position independent executable
+const ELF_DYN_BASE: usize = 0x1000_0000;
+
+/// Auxiliary vector types
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+/// User program loaded in a new address space
+pub struct UserImage {
+    pub space: AddressSpace,
+    pub entry: usize,
+    pub stack: usize,
+}
+
+fn segment_flags(ph: &ProgramHeader) -> Flags {
+    let mut flags = Flags::USER;
+    // writable page must be readable in riscv
+    if ph.flags & (PF_R | PF_W) != 0 {
+        flags |= Flags::READABLE;
+    }
+    if ph.flags & PF_W != 0 {
+        flags |= Flags::WRITABLE;
+    }
+    if ph.flags & PF_X != 0 {
+        flags |= Flags::EXECUTABLE;
+    }
+    flags
+}
+
+/// Map all `PT_LOAD` segments and copy data, return end of the highest segment
+fn load_segments(elf: &Elf, space: &mut AddressSpace, bias: usize) -> Result<usize, ElfError> {
+    // segments may share a page, so collect flags of all pages first
+    let mut pages = BTreeMap::new();
+    let mut max_end = 0;
+    for ph in elf.program_headers().filter(|ph| ph.p_type == PT_LOAD) {
+        if ph.memsz == 0 {
+            continue;
+        }
+        if ph.filesz > ph.memsz {
+            return Err(ElfError::BadSegment);
+        }
+        let start = (ph.vaddr as usize)
+            .checked_add(bias)
+            .ok_or(ElfError::BadSegment)?;
+        let end = start
+            .checked_add(ph.memsz as usize)
+            .ok_or(ElfError::BadSegment)?;
+        if end > USER_STACK_TOP - USER_STACK_SIZE {
+            return Err(ElfError::BadSegment);
+        }
+        max_end = max_end.max(end);
+
+        let flags = segment_flags(&ph);
+        for vpn in (start >> PAGE_SHIFT)..(align_up!(end, PAGE_SIZE) >> PAGE_SHIFT) {
+            *pages.entry(vpn).or_insert(flags) |= flags;
+        }
+    }
+
+    // pages are allocated when data is copied or on first touch
+    for (vpn, flags) in pages {
+        let va = vpn << PAGE_SHIFT;
+        if !space.map_vma(va, va + PAGE_SIZE, flags) {
+            return Err(ElfError::BadSegment);
+        }
+    }
+
+    for ph in elf.program_headers().filter(|ph| ph.p_type == PT_LOAD) {
+        if ph.memsz == 0 {
+            continue;
+        }
+        let data = elf.segment_data(&ph)?;
+        let va = (ph.vaddr as usize)
+            .checked_add(bias)
+            .ok_or(ElfError::BadSegment)?;
+        let va = VirtualAddr::new(va);
+        if !space.write(va, data) {
+            return Err(ElfError::NoMemory);
+        }
+    }
+    Ok(max_end)
+}
+
+/// User address of program headers, for `AT_PHDR`
+fn phdr_addr(elf: &Elf, bias: usize) -> Result<usize, ElfError> {
+    let phoff = elf.header().phoff;
+    let mut vaddr = None;
+    for ph in elf.program_headers() {
+        if ph.p_type == PT_PHDR {
+            vaddr = Some(ph.vaddr);
+            break;
+        }
+        // PT_PHDR is preferred, otherwise the first segment containing them
+        let contains = ph.offset <= phoff && phoff - ph.offset < ph.filesz;
+        if vaddr.is_none() && ph.p_type == PT_LOAD && contains {
+            vaddr = Some(
+                ph.vaddr
+                    .checked_add(phoff - ph.offset)
+                    .ok_or(ElfError::BadSegment)?,
+            );
+        }
+    }
+    match vaddr {
+        Some(vaddr) => (vaddr as usize)
+            .checked_add(bias)
+            .ok_or(ElfError::BadSegment),
+        None => Ok(0),
+    }
+}
+
+/// Setup user stack:
+///
+/// ```text
+/// USER_STACK_TOP -> +---------------------+
+///                   | argv and envp string|
+///                   | AT_RANDOM bytes     |
+///                   +---------------------+
+///                   | auxv, end by AT_NULL|
+///                   | envp, end by NULL   |
+///                   | argv, end by NULL   |
+///             sp -> | argc                |
+///                   +---------------------+
+/// ```
+fn setup_stack(
+    space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(usize, usize)],
+) -> Result<usize, ElfError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let flags = Flags::READABLE | Flags::WRITABLE;
+    if !space.map_vma(stack_bottom, USER_STACK_TOP, flags) {
+        return Err(ElfError::BadSegment);
+    }
+
+    let mut sp = USER_STACK_TOP;
+    let mut push = |data: &[u8]| -> Result<usize, ElfError> {
+        sp = sp
+            .checked_sub(data.len())
+            .filter(|&sp| sp >= stack_bottom)
+            .ok_or(ElfError::ArgumentTooLong)?;
+        if !space.write(VirtualAddr::new(sp), data) {
+            return Err(ElfError::NoMemory);
+        }
+        Ok(sp)
+    };
+
+    let mut push_str = |s: &str| -> Result<usize, ElfError> {
+        push(&[0])?;
+        push(s.as_bytes())
+    };
+    let argv: Vec<usize> = argv.iter().map(|s| push_str(s)).collect::<Result<_, _>>()?;
+    let envp: Vec<usize> = envp.iter().map(|s| push_str(s)).collect::<Result<_, _>>()?;
+
+    let random = (arch::read_time() as u128).wrapping_mul(0x9e3779b97f4a7c15f39cc0605cedc834);
+    let random = push(&random.to_le_bytes())?;
+
+    let mut vector = vec![argv.len()];
+    vector.extend_from_slice(&argv);
+    vector.push(0);
+    vector.extend_from_slice(&envp);
+    vector.push(0);
+    for &(key, value) in auxv {
+        vector.push(key);
+        vector.push(value);
+    }
+    vector.push(AT_RANDOM);
+    vector.push(random);
+    vector.push(AT_NULL);
+    vector.push(0);
+
+    let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+    // sp must be 16 bytes aligned
+    let sp = align_down!(sp - bytes.len(), 16);
+    if sp < stack_bottom {
+        return Err(ElfError::ArgumentTooLong);
+    }
+    if !space.write(VirtualAddr::new(sp), &bytes) {
+        return Err(ElfError::NoMemory);
+    }
+    Ok(sp)
+}
+
+/// Load ELF `data` into a new address space
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<UserImage, ElfError> {
+    let elf = Elf::parse(data)?;
+    if elf.program_headers().any(|ph| ph.p_type == PT_INTERP) {
+        return Err(ElfError::Interpreter);
+    }
+
+    let bias = if elf.is_dyn() { ELF_DYN_BASE } else { 0 };
+    let mut space = AddressSpace::new();
+    let end = load_segments(&elf, &mut space, bias)?;
+    space.init_heap(end);
+
+    let entry = elf.entry().checked_add(bias).ok_or(ElfError::BadSegment)?;
+    let auxv = [
+        (AT_PHDR, phdr_addr(&elf, bias)?),
+        (AT_PHENT, size_of::<ProgramHeader>()),
+        (AT_PHNUM, elf.header().phnum as usize),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, entry),
+    ];
+    let stack = setup_stack(&mut space, argv, envp, &auxv)?;
+
+    Ok(UserImage {
+        space,
+        entry,
+        stack,
+    })
+}
+
+/// Replace current task with user program `data`.
+///
+/// Only return on error.
+pub fn exec(data: &[u8], argv: &[&str], envp: &[&str]) -> ElfError {
+    match load(data, argv, envp) {
+        Ok(image) => {
+            current().set_address_space(image.space);
+            enter_user(image.entry, image.stack)
+        }
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,27 @@
+//! Process
+
+mod loader;
+mod processor;
+mod sched;
+mod task;
+mod wait_queue;
+
+use core::arch::global_asm;
+
+use crate::arch::{read_tp, write_tp};
+
+pub use loader::exec;
+pub use processor::{current, run_tasks};
+pub use sched::{scheduler_tick, yield_now};
+pub use task::*;
+pub use wait_queue::WaitQueue;
+
+global_asm!(include_str!("./switch.asm"));
+
+pub fn hartid() -> usize {
+    read_tp()
+}
+
+pub fn init(hart_id: usize) {
+    write_tp(hart_id);
+}
@@ -0,0 +1,137 @@
+//! Per hart processor state and the idle task
+
+use alloc::sync::Arc;
+use core::hint::spin_loop;
+use core::sync::atomic::Ordering;
+
+use super::sched;
+use super::task::{TaskContext, __switch};
+use super::{Task, TaskState};
+use crate::interrupt;
+use crate::mm::mapping::load_kernel_pagetable;
+use crate::sync::PerCpu;
+
+struct Processor {
+    /// Task running on this hart
+    current: Option<Arc<Task>>,
+    /// Context of the idle task
+    idle_context: TaskContext,
+}
+
+impl const Default for Processor {
+    fn default() -> Self {
+        Self {
+            current: None,
+            idle_context: TaskContext::new_zeroed(),
+        }
+    }
+}
+
+static PROCESSORS: PerCpu<Processor> = PerCpu::new();
+
+/// Get current running task
+///
+/// # Panic
+/// Panic if called from the idle task
+pub fn current() -> Arc<Task> {
+    PROCESSORS
+        .get()
+        .current
+        .clone()
+        .expect("No task running on this hart")
+}
+
+/// Whether a task is running on this hart
+pub(super) fn has_current() -> bool {
+    PROCESSORS.get().current.is_some()
+}
+
+/// Put a new task to run queue
+pub(super) fn wakeup_new(task: Arc<Task>) {
+    sched::enqueue(task);
+}
+
+/// Wake up a blocked task, do nothing if it is not blocked
+pub(super) fn wakeup(task: Arc<Task>) {
+    if task.cmpxchg_state(TaskState::Blocked, TaskState::Runnable) {
+        sched::enqueue(task);
+    }
+}
+
+/// Switch from current task to the idle task of this hart.
+///
+/// The caller should set state of current task before, a `Running` task
+/// will be put back to run queue.
+pub(super) fn schedule() {
+    let irq = interrupt::intr();
+    interrupt::intr_off();
+
+    let (current, idle) = {
+        let mut processor = PROCESSORS.get();
+        let current = processor
+            .current
+            .as_ref()
+            .expect("schedule without task")
+            .context_ptr();
+        (current, &mut processor.idle_context as *mut TaskContext)
+    };
+    unsafe {
+        __switch(current, idle);
+    }
+
+    if irq {
+        interrupt::intr_on();
+    }
+}
+
+pub(super) fn exit_current(code: isize) -> ! {
+    current().do_exit(code);
+    schedule();
+    unreachable!("Exited task resumed");
+}
+
+/// Run `next` on this hart until it switch back to scheduler
+fn run(next: Arc<Task>) {
+    // `next` may be still switching out on another hart
+    while next.on_cpu.load(Ordering::Acquire) {
+        spin_loop();
+    }
+    next.on_cpu.store(true, Ordering::Relaxed);
+    next.set_state(TaskState::Running);
+
+    next.activate_address_space();
+    let next_context = next.context_ptr();
+    let idle = {
+        let mut processor = PROCESSORS.get();
+        processor.current = Some(next);
+        &mut processor.idle_context as *mut TaskContext
+    };
+    unsafe {
+        __switch(idle, next_context);
+    }
+
+    let prev = PROCESSORS.get().current.take().unwrap();
+    if prev.deactivate_address_space() {
+        // user pagetable may be freed once `prev` is dropped
+        load_kernel_pagetable();
+    }
+    if prev.cmpxchg_state(TaskState::Running, TaskState::Runnable) {
+        sched::enqueue(prev.clone());
+    }
+    // Must be the last access to `prev`, other harts may run it once cleared
+    prev.on_cpu.store(false, Ordering::Release);
+}
+
+/// Idle task of each hart, run tasks in run queue and wait for interrupt
+/// if there is nothing to run or steal. Never return.
+pub fn run_tasks() -> ! {
+    loop {
+        interrupt::intr_off();
+        match sched::pick_next() {
+            Some(task) => run(task),
+            // pending interrupt will wake up hart even if interrupt is off
+            None => sched::idle_wait(),
+        }
+        interrupt::intr_on();
+    }
+}
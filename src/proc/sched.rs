@@ -0,0 +1,189 @@
+//! Preemptive round-robin scheduler
+//!
+//! Each hart has its own run queue. A running task is preempted by the timer
+//! interrupt once it used up its time slice, and put to the tail of the queue.
+//!
+//! # Load balance
+//! - An idle hart steals a task from the busiest hart before it sleeps.
+//! - Every `BALANCE_INTERVAL` ticks, a hart pulls tasks from the busiest
+//!   hart until their load is even.
+//! - Enqueue a task will send IPI to an idle hart, so it can steal the task.
+
+use alloc::collections::LinkedList;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::hartid;
+use super::processor::{has_current, schedule};
+use super::Task;
+use crate::sbi::send_ipi;
+use crate::sync::{PerCpu, Spin, NCPU};
+
+/// Time slice in timer ticks
+const TIME_SLICE: usize = 4;
+
+/// Periodic load balance interval in timer ticks
+const BALANCE_INTERVAL: usize = 8;
+
+/// Bitmap of harts waiting for interrupt in idle task
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+struct RunQueue {
+    /// Tasks may be stolen by other harts, so protect by lock
+    tasks: Spin<LinkedList<Arc<Task>>>,
+    /// Length of `tasks`, can be read without lock
+    nr_running: AtomicUsize,
+    /// Ticks left of the running task
+    ticks_left: AtomicUsize,
+    /// Ticks since last periodic balance
+    balance_ticks: AtomicUsize,
+}
+
+impl const Default for RunQueue {
+    fn default() -> Self {
+        Self {
+            tasks: Spin::new(LinkedList::new()),
+            nr_running: AtomicUsize::new(0),
+            ticks_left: AtomicUsize::new(0),
+            balance_ticks: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl RunQueue {
+    fn len(&self) -> usize {
+        self.nr_running.load(Ordering::Relaxed)
+    }
+
+    fn push_back(&self, task: Arc<Task>) {
+        let mut tasks = self.tasks.lock();
+        tasks.push_back(task);
+        self.nr_running.store(tasks.len(), Ordering::Relaxed);
+    }
+
+    fn pop_front(&self) -> Option<Arc<Task>> {
+        let mut tasks = self.tasks.lock();
+        let task = tasks.pop_front();
+        self.nr_running.store(tasks.len(), Ordering::Relaxed);
+        task
+    }
+
+    /// Steal the task which has waited the shortest time
+    fn pop_back(&self) -> Option<Arc<Task>> {
+        let mut tasks = self.tasks.lock();
+        let task = tasks.pop_back();
+        self.nr_running.store(tasks.len(), Ordering::Relaxed);
+        task
+    }
+}
+
+static RUN_QUEUES: PerCpu<RunQueue> = PerCpu::new();
+
+/// Put a runnable task to the run queue of current hart
+pub(super) fn enqueue(task: Arc<Task>) {
+    RUN_QUEUES.get().push_back(task);
+    kick_idle_hart();
+}
+
+/// Send IPI to an idle hart, so it can steal the new task
+fn kick_idle_hart() {
+    let idle = IDLE_HARTS.load(Ordering::SeqCst) & !(1 << hartid());
+    if idle != 0 {
+        send_ipi(1 << idle.trailing_zeros());
+    }
+}
+
+/// Run queue of `hart`
+fn remote_queue(hart: usize) -> &'static RunQueue {
+    // run queue is only accessed by its lock and atomics
+    unsafe { RUN_QUEUES.get_remote(hart) }
+}
+
+/// Find the hart with most runnable tasks, except current hart
+fn find_busiest() -> Option<(usize, usize)> {
+    let this = hartid();
+    (0..NCPU)
+        .filter(|&hart| hart != this)
+        .map(|hart| (hart, remote_queue(hart).len()))
+        .filter(|&(_, nr)| nr > 0)
+        .max_by_key(|&(_, nr)| nr)
+}
+
+/// Steal a task from the busiest hart
+fn steal() -> Option<Arc<Task>> {
+    let (busiest, _) = find_busiest()?;
+    remote_queue(busiest).pop_back()
+}
+
+/// Pull tasks from the busiest hart to even the load
+fn load_balance() {
+    let rq = RUN_QUEUES.get();
+    if let Some((busiest, nr)) = find_busiest() {
+        let local = rq.len();
+        if nr <= local + 1 {
+            return;
+        }
+
+        let remote = remote_queue(busiest);
+        for _ in 0..(nr - local) / 2 {
+            match remote.pop_back() {
+                Some(task) => rq.push_back(task),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Pick next task to run on current hart, steal one if run queue is empty
+pub(super) fn pick_next() -> Option<Arc<Task>> {
+    let rq = RUN_QUEUES.get();
+    let next = rq.pop_front().or_else(steal);
+    if next.is_some() {
+        rq.ticks_left.store(TIME_SLICE, Ordering::Relaxed);
+    }
+    next
+}
+
+/// Whether there is any runnable task in all harts
+fn has_runnable() -> bool {
+    (0..NCPU).any(|hart| remote_queue(hart).len() > 0)
+}
+
+/// Idle hart wait for interrupt, IPI will be sent to it when new task
+/// is enqueued.
+pub(super) fn idle_wait() {
+    let mask = 1 << hartid();
+    IDLE_HARTS.fetch_or(mask, Ordering::SeqCst);
+    // check again, task may be enqueued before we set idle
+    if !has_runnable() {
+        crate::arch::wait_for_interrupt();
+    }
+    IDLE_HARTS.fetch_and(!mask, Ordering::SeqCst);
+}
+
+/// Called by timer interrupt, preempt current task if its time slice expired
+pub fn scheduler_tick() {
+    let expired = {
+        let rq = RUN_QUEUES.get();
+        if rq.balance_ticks.fetch_add(1, Ordering::Relaxed) + 1 >= BALANCE_INTERVAL {
+            rq.balance_ticks.store(0, Ordering::Relaxed);
+            load_balance();
+        }
+
+        if !has_current() {
+            // idle
+            return;
+        }
+        let ticks_left = rq.ticks_left.load(Ordering::Relaxed).saturating_sub(1);
+        rq.ticks_left.store(ticks_left, Ordering::Relaxed);
+        ticks_left == 0
+    };
+    if expired {
+        yield_now();
+    }
+}
+
+/// Give up the hart and put current task to the tail of run queue
+pub fn yield_now() {
+    schedule();
+}
@@ -0,0 +1,37 @@
+# task context switch
+
+.altmacro
+.set    REG_SIZE, 8
+
+.macro SAVE_SN n
+    sd  s\n, (\n + 2)*REG_SIZE(a0)
+.endm
+
+.macro LOAD_SN n
+    ld  s\n, (\n + 2)*REG_SIZE(a1)
+.endm
+
+    .section .text
+    .globl __switch
+# __switch(current: *mut TaskContext, next: *const TaskContext)
+# save callee registers of current task and resume next task
+__switch:
+    sd      ra, 0*REG_SIZE(a0)
+    sd      sp, 1*REG_SIZE(a0)
+    # save s0 to s11
+    .set    n, 0
+    .rept   12
+        SAVE_SN %n
+        .set    n, n + 1
+    .endr
+
+    ld      ra, 0*REG_SIZE(a1)
+    ld      sp, 1*REG_SIZE(a1)
+    # restore s0 to s11
+    .set    n, 0
+    .rept   12
+        LOAD_SN %n
+        .set    n, n + 1
+    .endr
+
+    ret
@@ -0,0 +1,265 @@
+//! Kernel thread
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU8, AtomicUsize, Ordering};
+
+use super::processor::{current, exit_current};
+use super::WaitQueue;
+use crate::interrupt::{self, Context};
+use crate::mm::addr_space::AddressSpace;
+use crate::mm::kstack::KernelStack;
+use crate::sync::{Spin, SpinGuard};
+
+/// Reserved at kernel stack top for user `Context` and hart id of kernel,
+/// see `interrupt.asm`
+const USER_CONTEXT_SIZE: usize = size_of::<Context>() + 16;
+
+/// Callee saved registers, saved and restored by `__switch`
+#[repr(C)]
+#[derive(Debug)]
+pub struct TaskContext {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+impl TaskContext {
+    pub const fn new_zeroed() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+        }
+    }
+
+    /// Context that starts at `entry` with stack pointer `sp`
+    fn new(entry: usize, sp: usize) -> Self {
+        Self {
+            ra: entry,
+            sp,
+            s: [0; 12],
+        }
+    }
+}
+
+extern "C" {
+    /// switch context in `switch.asm`
+    pub(super) fn __switch(current: *mut TaskContext, next: *const TaskContext);
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    /// In a run queue, waiting for a hart
+    Runnable,
+    /// Running on a hart
+    Running,
+    /// Waiting in a `WaitQueue`
+    Blocked,
+    /// Exited, waiting to be joined
+    Exited,
+}
+
+impl From<u8> for TaskState {
+    fn from(state: u8) -> Self {
+        match state {
+            0 => TaskState::Runnable,
+            1 => TaskState::Running,
+            2 => TaskState::Blocked,
+            3 => TaskState::Exited,
+            _ => panic!("Bad task state {}", state),
+        }
+    }
+}
+
+type TaskEntry = Box<dyn FnOnce() -> isize + Send>;
+
+pub struct Task {
+    tid: usize,
+    state: AtomicU8,
+    /// Context is still in use by a hart, it can't be resumed before cleared
+    pub(super) on_cpu: AtomicBool,
+    context: UnsafeCell<TaskContext>,
+    kstack: KernelStack,
+    entry: Spin<Option<TaskEntry>>,
+    exit_code: AtomicIsize,
+    exit_wait: WaitQueue,
+    /// User address space, `None` for kernel thread
+    addr_space: Spin<Option<AddressSpace>>,
+}
+
+static NEXT_TID: AtomicUsize = AtomicUsize::new(1);
+
+impl Task {
+    fn new(entry: TaskEntry) -> Arc<Self> {
+        let kstack = KernelStack::new().expect("No memory for kernel stack");
+        let context = TaskContext::new(task_entry as usize, kstack.top() - USER_CONTEXT_SIZE);
+        Arc::new(Self {
+            tid: NEXT_TID.fetch_add(1, Ordering::Relaxed),
+            state: AtomicU8::new(TaskState::Runnable as u8),
+            on_cpu: AtomicBool::new(false),
+            context: UnsafeCell::new(context),
+            kstack,
+            entry: Spin::new(Some(entry)),
+            exit_code: AtomicIsize::new(0),
+            exit_wait: WaitQueue::new(),
+            addr_space: Spin::new(None),
+        })
+    }
+
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state.load(Ordering::Acquire).into()
+    }
+
+    pub(super) fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Change state from `current` to `new`, return false if state is not `current`
+    pub(super) fn cmpxchg_state(&self, current: TaskState, new: TaskState) -> bool {
+        self.state
+            .compare_exchange(
+                current as u8,
+                new as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+
+    pub(super) fn context_ptr(&self) -> *mut TaskContext {
+        self.context.get()
+    }
+
+    /// Set user address space of current task and switch to it
+    pub fn set_address_space(&self, space: AddressSpace) {
+        let mut addr_space = self.addr_space.lock();
+        if let Some(old) = addr_space.as_ref() {
+            old.deactivate();
+        }
+        space.activate();
+        // old address space is dropped after switched
+        *addr_space = Some(space);
+    }
+
+    /// User address space of this task, `None` for kernel thread
+    pub fn address_space(&self) -> SpinGuard<'_, Option<AddressSpace>> {
+        self.addr_space.lock()
+    }
+
+    /// Switch to address space of this task, return false if it is a
+    /// kernel thread
+    pub(super) fn activate_address_space(&self) -> bool {
+        match self.addr_space.lock().as_ref() {
+            Some(space) => {
+                space.activate();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Current hart switches away from address space of this task, return
+    /// false if it is a kernel thread
+    pub(super) fn deactivate_address_space(&self) -> bool {
+        match self.addr_space.lock().as_ref() {
+            Some(space) => {
+                space.deactivate();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// User context saved when trap from user mode
+    pub fn user_context(&self) -> *mut Context {
+        (self.kstack.top() - USER_CONTEXT_SIZE) as *mut Context
+    }
+
+    /// Mark task exited and wake up all joiner
+    pub(super) fn do_exit(&self, code: isize) {
+        self.exit_code.store(code, Ordering::Relaxed);
+        self.set_state(TaskState::Exited);
+        self.exit_wait.wake_all();
+    }
+}
+
+unsafe impl Sync for Task {}
+unsafe impl Send for Task {}
+
+/// First code run by a new task, the `ra` of a new `TaskContext`
+extern "C" fn task_entry() -> ! {
+    // scheduler switch to us with interrupt off
+    interrupt::intr_on();
+
+    let entry = current().entry.lock().take().unwrap();
+    exit(entry())
+}
+
+pub struct JoinHandle {
+    task: Arc<Task>,
+}
+
+impl JoinHandle {
+    /// Wait for task exit and return its exit code
+    pub fn join(self) -> isize {
+        let task = &self.task;
+        task.exit_wait
+            .wait_until(|| task.state() == TaskState::Exited);
+        task.exit_code.load(Ordering::Relaxed)
+    }
+}
+
+/// Spawn a new kernel thread
+pub fn spawn<F>(f: F) -> JoinHandle
+where
+    F: FnOnce() -> isize + Send + 'static,
+{
+    let task = Task::new(Box::new(f));
+    super::processor::wakeup_new(task.clone());
+    JoinHandle { task }
+}
+
+/// Enter user mode at `entry` with user stack `sp`, current task become a
+/// user task and never return to kernel thread.
+pub fn enter_user(entry: usize, sp: usize) -> ! {
+    let context = current().user_context();
+    unsafe {
+        *context = Context::new_user(entry, sp);
+        interrupt::restore(context)
+    }
+}
+
+/// Fork current user task, the child shares pages copy-on-write and
+/// returns 0 from the system call. Return tid of the child, or `None` if
+/// current task is a kernel thread or out of memory.
+pub fn fork() -> Option<usize> {
+    let parent = current();
+    let space = parent.address_space().as_mut()?.fork()?;
+    let mut context = unsafe { (*parent.user_context()).clone() };
+    context.regs[10] = 0;
+
+    let task = Task::new(Box::new(move || {
+        let task = current();
+        task.set_address_space(space);
+        unsafe {
+            *task.user_context() = context;
+            interrupt::restore(task.user_context())
+        }
+    }));
+    let tid = task.tid();
+    super::processor::wakeup_new(task);
+    Some(tid)
+}
+
+/// Exit current kernel thread with `code`
+pub fn exit(code: isize) -> ! {
+    exit_current(code)
+}
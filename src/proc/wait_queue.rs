@@ -0,0 +1,62 @@
+//! Wait queue
+//!
+//! Tasks sleep in wait queue until a condition is satisfied.
+
+use alloc::collections::LinkedList;
+use alloc::sync::Arc;
+use core::mem;
+
+use super::processor::{current, schedule, wakeup};
+use super::{Task, TaskState};
+use crate::sync::Spin;
+
+pub struct WaitQueue {
+    waiters: Spin<LinkedList<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Spin::new(LinkedList::new()),
+        }
+    }
+
+    /// Block current task until `cond` return true.
+    ///
+    /// `cond` is checked with the queue locked, so a waker which change
+    /// the condition before `wake_*` will never be missed.
+    pub fn wait_until<F>(&self, cond: F)
+    where
+        F: Fn() -> bool,
+    {
+        loop {
+            {
+                let mut waiters = self.waiters.lock();
+                if cond() {
+                    return;
+                }
+                let task = current();
+                task.set_state(TaskState::Blocked);
+                waiters.push_back(task);
+            }
+            schedule();
+        }
+    }
+
+    /// Wake up the first waiter
+    #[allow(dead_code)]
+    pub fn wake_one(&self) {
+        let task = self.waiters.lock().pop_front();
+        if let Some(task) = task {
+            wakeup(task);
+        }
+    }
+
+    /// Wake up all waiters
+    pub fn wake_all(&self) {
+        let waiters = mem::take(&mut *self.waiters.lock());
+        for task in waiters {
+            wakeup(task);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(unused)]
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 macro_rules! sbi_call {
     ($which: expr, $arg0: expr, $arg1: expr, $arg2: expr) => {{
@@ -10,7 +11,7 @@ macro_rules! sbi_call {
                 "ecall",
                 inout("x10") $arg0 => ret,
                 in("x11") $arg1,
-                in("x12") $arg2,
+                in("x12") $arg2 as usize,
                 in("x17") $which,
             );
         }
@@ -32,6 +33,28 @@ macro_rules! sbi_call0 {
     }};
 }
 
+/// SBI v0.2 call with extension `$eid` and function `$fid`, return
+/// (error, value)
+macro_rules! sbi_ecall {
+    ($eid: expr, $fid: expr, $arg0: expr, $arg1: expr, $arg2: expr, $arg3: expr, $arg4: expr) => {{
+        let error: usize;
+        let value: usize;
+        unsafe {
+            asm!(
+                "ecall",
+                inlateout("x10") $arg0 as usize => error,
+                inlateout("x11") $arg1 as usize => value,
+                in("x12") $arg2 as usize,
+                in("x13") $arg3 as usize,
+                in("x14") $arg4 as usize,
+                in("x16") $fid,
+                in("x17") $eid,
+            );
+        }
+        (error as isize, value)
+    }};
+}
+
 const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
@@ -42,6 +65,19 @@ const SBI_REMOTE_SFENCE_VMA: usize = 6;
 const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+const SBI_EXT_BASE: usize = 0x10;
+const SBI_BASE_PROBE_EXTENSION: usize = 3;
+
+/// RFENCE extension, "RFNC"
+const SBI_EXT_RFENCE: usize = 0x5246_4E43;
+const SBI_RFENCE_SFENCE_VMA: usize = 1;
+const SBI_RFENCE_SFENCE_VMA_ASID: usize = 2;
+
+/// Whether RFENCE extension is available, 0 if not probed yet
+static HAS_RFENCE: AtomicU8 = AtomicU8::new(0);
+const RFENCE_YES: u8 = 1;
+const RFENCE_NO: u8 = 2;
+
 /// put a character to console
 pub fn console_putchar(c: usize) {
     sbi_call!(SBI_CONSOLE_PUTCHAR, c, 0, 0);
@@ -58,7 +94,68 @@ pub fn shutdown() -> ! {
     unreachable!()
 }
 
+/// send inter-processor interrupt to harts in `hart_mask`
+pub fn send_ipi(hart_mask: usize) {
+    sbi_call!(SBI_SEND_IPI, &hart_mask as *const usize as usize, 0, 0);
+}
+
 /// set timer
 pub fn set_timer(stime_val: u64) {
     sbi_call!(SBI_SET_TIMER, stime_val, 0, 0);
 }
+
+/// whether SBI implements extension `eid`
+fn probe_extension(eid: usize) -> bool {
+    let (error, value) = sbi_ecall!(SBI_EXT_BASE, SBI_BASE_PROBE_EXTENSION, eid, 0, 0, 0, 0);
+    error == 0 && value != 0
+}
+
+fn has_rfence() -> bool {
+    match HAS_RFENCE.load(Ordering::Relaxed) {
+        RFENCE_YES => true,
+        RFENCE_NO => false,
+        _ => {
+            let has = probe_extension(SBI_EXT_RFENCE);
+            let state = if has { RFENCE_YES } else { RFENCE_NO };
+            HAS_RFENCE.store(state, Ordering::Relaxed);
+            has
+        }
+    }
+}
+
+/// execute `sfence.vma` of `start` ~ `start + size` on harts in `hart_mask`
+pub fn remote_sfence_vma(hart_mask: usize, start: usize, size: usize) {
+    if has_rfence() {
+        sbi_ecall!(
+            SBI_EXT_RFENCE,
+            SBI_RFENCE_SFENCE_VMA,
+            hart_mask,
+            0,
+            start,
+            size,
+            0
+        );
+    } else {
+        let mask = &hart_mask as *const usize as usize;
+        sbi_call!(SBI_REMOTE_SFENCE_VMA, mask, start, size);
+    }
+}
+
+/// execute `sfence.vma` of `start` ~ `start + size` for `asid` on harts in
+/// `hart_mask`
+pub fn remote_sfence_vma_asid(hart_mask: usize, start: usize, size: usize, asid: usize) {
+    if has_rfence() {
+        sbi_ecall!(
+            SBI_EXT_RFENCE,
+            SBI_RFENCE_SFENCE_VMA_ASID,
+            hart_mask,
+            0,
+            start,
+            size,
+            asid
+        );
+    } else {
+        let mask = &hart_mask as *const usize as usize;
+        sbi_ecall!(SBI_REMOTE_SFENCE_VMA_ASID, 0, mask, start, size, asid, 0);
+    }
+}
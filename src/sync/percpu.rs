@@ -13,7 +13,7 @@ use crate::proc::hartid;
 
 macro_rules! NCPU {
     ($n:expr) => {
-        const NCPU: usize = $n;
+        pub const NCPU: usize = $n;
 
         macro_rules! percpu_arr {() => {arr![Default::default(); $n]};}
     };
@@ -52,6 +52,19 @@ where
         PerCpuGuard { percpu: &self, irq }
     }
 
+    /// Get data of other hart.
+    ///
+    /// # Safety
+    /// The owner hart may access it at the same time, so `T` must protect
+    /// itself by lock or atomic, and the data must never be accessed by
+    /// `DerefMut` of `PerCpuGuard`, which would alias the returned reference.
+    pub unsafe fn get_remote(&self, hart: usize) -> &T
+    where
+        T: Sync,
+    {
+        &(*self.data.get())[hart]
+    }
+
     fn unlock(&self, irq: bool) {
         fence(Ordering::Release);
         if irq {
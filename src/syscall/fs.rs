@@ -0,0 +1,106 @@
+//! File system call, only stdin, stdout and stderr are supported now
+
+use core::mem::size_of;
+
+use super::{with_user, EBADF, EFAULT, EINVAL};
+use crate::console;
+use crate::proc::yield_now;
+use crate::sbi::console_getchar;
+
+const STDIN: usize = 0;
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+/// Copy user data to kernel by chunk of this size
+const BUFFER_SIZE: usize = 256;
+
+/// Limit of `iovcnt` in `writev`
+const IOV_MAX: usize = 1024;
+
+/// Read from console, block until at least one byte is available
+pub fn sys_read(args: &[usize; 6]) -> isize {
+    let (fd, buf, count) = (args[0], args[1], args[2]);
+    if fd != STDIN {
+        return -EBADF;
+    }
+    if count == 0 {
+        return 0;
+    }
+
+    let mut data = [0u8; BUFFER_SIZE];
+    let mut len = 0;
+    while len < count.min(BUFFER_SIZE) {
+        match console_getchar() {
+            // nothing to read
+            usize::MAX => {
+                if len > 0 {
+                    break;
+                }
+                yield_now();
+            }
+            c => {
+                data[len] = c as u8;
+                len += 1;
+            }
+        }
+    }
+
+    if with_user(|space| space.copy_to_user(buf, &data[..len])) {
+        len as isize
+    } else {
+        -EFAULT
+    }
+}
+
+/// Write user buffer `buf` ~ `buf + count` to console
+fn write_console(buf: usize, count: usize) -> isize {
+    let mut data = [0u8; BUFFER_SIZE];
+    let mut written = 0;
+    while written < count {
+        let n = (count - written).min(BUFFER_SIZE);
+        if !with_user(|space| space.copy_from_user(buf + written, &mut data[..n])) {
+            return -EFAULT;
+        }
+        console::write_bytes(&data[..n]);
+        written += n;
+    }
+    written as isize
+}
+
+pub fn sys_write(args: &[usize; 6]) -> isize {
+    let (fd, buf, count) = (args[0], args[1], args[2]);
+    match fd {
+        STDOUT | STDERR => write_console(buf, count),
+        _ => -EBADF,
+    }
+}
+
+/// Write `iovcnt` buffers described by `struct iovec` array `iov`
+pub fn sys_writev(args: &[usize; 6]) -> isize {
+    let (fd, iov, iovcnt) = (args[0], args[1], args[2]);
+    if fd != STDOUT && fd != STDERR {
+        return -EBADF;
+    }
+    if iovcnt > IOV_MAX {
+        return -EINVAL;
+    }
+
+    let mut total = 0;
+    for i in 0..iovcnt {
+        // struct iovec { void *iov_base; size_t iov_len; }
+        let mut vec = [0u8; 2 * size_of::<usize>()];
+        let addr = iov + i * vec.len();
+        if !with_user(|space| space.copy_from_user(addr, &mut vec)) {
+            return -EFAULT;
+        }
+        let base = usize::from_le_bytes(vec[..8].try_into().unwrap());
+        let len = usize::from_le_bytes(vec[8..].try_into().unwrap());
+
+        let ret = write_console(base, len);
+        if ret < 0 {
+            return ret;
+        }
+        total += ret;
+    }
+    total
+}
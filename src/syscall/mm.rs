@@ -0,0 +1,73 @@
+//! Memory management system call, only anonymous mapping is supported
+
+use super::{with_user, EINVAL, ENOMEM};
+use crate::mm::addr_space::USER_SPACE_END;
+use crate::mm::mapping::Flags;
+use crate::mm::PAGE_MASK;
+
+const PROT_READ: usize = 1 << 0;
+const PROT_WRITE: usize = 1 << 1;
+const PROT_EXEC: usize = 1 << 2;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+/// Set program break to `addr`, return the new program break, or current
+/// one if failed. `brk(0)` queries current program break.
+pub fn sys_brk(args: &[usize; 6]) -> isize {
+    with_user(|space| space.set_brk(args[0])) as isize
+}
+
+fn prot_flags(prot: usize) -> Flags {
+    let mut flags = Flags::empty();
+    // writable page must be readable in riscv
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        flags |= Flags::READABLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= Flags::WRITABLE;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags |= Flags::EXECUTABLE;
+    }
+    flags
+}
+
+pub fn sys_mmap(args: &[usize; 6]) -> isize {
+    let (addr, len, prot, flags) = (args[0], args[1], args[2], args[3]);
+    if len == 0 || flags & MAP_ANONYMOUS == 0 {
+        return -EINVAL;
+    }
+    if flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+        return -EINVAL;
+    }
+    if len > USER_SPACE_END {
+        return -ENOMEM;
+    }
+    // a page without R, W and X is a pointer to next level in riscv,
+    // PROT_NONE is not supported yet
+    let prot = prot_flags(prot);
+    if prot.is_empty() {
+        return -EINVAL;
+    }
+
+    match with_user(|space| space.mmap(addr, len, prot, flags & MAP_FIXED != 0)) {
+        Some(addr) => addr as isize,
+        None => -ENOMEM,
+    }
+}
+
+pub fn sys_munmap(args: &[usize; 6]) -> isize {
+    let (addr, len) = (args[0], args[1]);
+    if addr & PAGE_MASK != 0 || len == 0 {
+        return -EINVAL;
+    }
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => {}
+        _ => return -EINVAL,
+    }
+    with_user(|space| space.unmap(addr, len));
+    0
+}
@@ -0,0 +1,75 @@
+//! System call
+//!
+//! Follow the Linux riscv64 ABI: syscall number in `a7`, arguments in
+//! `a0` ~ `a5`, return value in `a0`, and error is returned as `-errno`.
+
+mod fs;
+mod mm;
+mod process;
+mod time;
+
+use crate::interrupt::Context;
+use crate::mm::addr_space::AddressSpace;
+use crate::proc::current;
+
+const SYS_READ: usize = 63;
+const SYS_WRITE: usize = 64;
+const SYS_WRITEV: usize = 66;
+const SYS_EXIT: usize = 93;
+const SYS_EXIT_GROUP: usize = 94;
+const SYS_SET_TID_ADDRESS: usize = 96;
+const SYS_CLOCK_GETTIME: usize = 113;
+const SYS_SCHED_YIELD: usize = 124;
+const SYS_GETPID: usize = 172;
+const SYS_BRK: usize = 214;
+const SYS_MUNMAP: usize = 215;
+const SYS_CLONE: usize = 220;
+const SYS_MMAP: usize = 222;
+
+const NR_SYSCALLS: usize = SYS_MMAP + 1;
+
+pub const EBADF: isize = 9;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+pub const EINVAL: isize = 22;
+pub const ENOSYS: isize = 38;
+
+type Syscall = fn(&[usize; 6]) -> isize;
+
+static SYSCALL_TABLE: [Option<Syscall>; NR_SYSCALLS] = {
+    let mut table: [Option<Syscall>; NR_SYSCALLS] = [None; NR_SYSCALLS];
+    table[SYS_READ] = Some(fs::sys_read);
+    table[SYS_WRITE] = Some(fs::sys_write);
+    table[SYS_WRITEV] = Some(fs::sys_writev);
+    table[SYS_EXIT] = Some(process::sys_exit);
+    table[SYS_EXIT_GROUP] = Some(process::sys_exit_group);
+    table[SYS_SET_TID_ADDRESS] = Some(process::sys_set_tid_address);
+    table[SYS_CLOCK_GETTIME] = Some(time::sys_clock_gettime);
+    table[SYS_SCHED_YIELD] = Some(process::sys_sched_yield);
+    table[SYS_GETPID] = Some(process::sys_getpid);
+    table[SYS_BRK] = Some(mm::sys_brk);
+    table[SYS_MUNMAP] = Some(mm::sys_munmap);
+    table[SYS_CLONE] = Some(process::sys_clone);
+    table[SYS_MMAP] = Some(mm::sys_mmap);
+    table
+};
+
+/// Run `f` with address space of current task
+fn with_user<T>(f: impl FnOnce(&mut AddressSpace) -> T) -> T {
+    let task = current();
+    let mut space = task.address_space();
+    f(space.as_mut().expect("Syscall from kernel thread"))
+}
+
+/// Handle ecall from user mode, `sepc` should point to next instruction
+pub fn syscall(context: &mut Context) {
+    let id = context.regs[17];
+    let mut args = [0; 6];
+    args.copy_from_slice(&context.regs[10..16]);
+
+    let ret = match SYSCALL_TABLE.get(id).copied().flatten() {
+        Some(handler) => handler(&args),
+        None => -ENOSYS,
+    };
+    context.regs[10] = ret as usize;
+}
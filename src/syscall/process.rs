@@ -0,0 +1,42 @@
+//! Process related system call, a task is a process with single thread now
+
+use super::{EINVAL, ENOMEM};
+use crate::proc::{current, exit, fork, yield_now};
+
+/// Signal sent to parent when child exits, the only flag of `fork()`
+const SIGCHLD: usize = 17;
+
+pub fn sys_exit(args: &[usize; 6]) -> isize {
+    exit(args[0] as i32 as isize)
+}
+
+/// Same as `exit` as there is only one thread in a process
+pub fn sys_exit_group(args: &[usize; 6]) -> isize {
+    exit(args[0] as i32 as isize)
+}
+
+/// Called by libc at startup, `clear_child_tid` is ignored
+pub fn sys_set_tid_address(_args: &[usize; 6]) -> isize {
+    current().tid() as isize
+}
+
+pub fn sys_sched_yield(_args: &[usize; 6]) -> isize {
+    yield_now();
+    0
+}
+
+pub fn sys_getpid(_args: &[usize; 6]) -> isize {
+    current().tid() as isize
+}
+
+/// Only `fork()` is supported, which is `clone(SIGCHLD, 0)`
+pub fn sys_clone(args: &[usize; 6]) -> isize {
+    let (flags, stack) = (args[0], args[1]);
+    if flags != SIGCHLD || stack != 0 {
+        return -EINVAL;
+    }
+    match fork() {
+        Some(tid) => tid as isize,
+        None => -ENOMEM,
+    }
+}
@@ -0,0 +1,29 @@
+//! Time related system call
+
+use super::{with_user, EFAULT, EINVAL};
+use crate::timer::read_time_ns;
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// There is no RTC, so `CLOCK_REALTIME` is time since boot as well
+pub fn sys_clock_gettime(args: &[usize; 6]) -> isize {
+    let (clock, tp) = (args[0], args[1]);
+    if clock != CLOCK_REALTIME && clock != CLOCK_MONOTONIC {
+        return -EINVAL;
+    }
+
+    // struct timespec { time_t tv_sec; long tv_nsec; }
+    let ns = read_time_ns();
+    let mut timespec = [0u8; 16];
+    timespec[..8].copy_from_slice(&(ns / NSEC_PER_SEC).to_le_bytes());
+    timespec[8..].copy_from_slice(&(ns % NSEC_PER_SEC).to_le_bytes());
+
+    if with_user(|space| space.copy_to_user(tp, &timespec)) {
+        0
+    } else {
+        -EFAULT
+    }
+}
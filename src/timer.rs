@@ -9,14 +9,13 @@ static TICK: SeqLock<u64> = SeqLock::new(0);
 
 const INTERVAL: usize = 100000;
 
+/// Frequency of `time` csr in qemu virt machine
+pub const CLOCK_FREQ: u64 = 10_000_000;
+
 /// set next timer interrupt
 pub fn set_next_timeout() {
     set_timer((arch::read_time() + INTERVAL) as u64);
-    let mut tick = TICK.writer_lock();
-    *tick += 1;
-    if *tick % 100 == 0 {
-        println!("tick {}", *tick);
-    }
+    *TICK.writer_lock() += 1;
 }
 
 #[allow(dead_code)]
@@ -24,6 +23,11 @@ pub fn read_tick() -> u64 {
     *TICK.read()
 }
 
+/// Time since boot in nanoseconds
+pub fn read_time_ns() -> u64 {
+    arch::read_time() as u64 * (1_000_000_000 / CLOCK_FREQ)
+}
+
 pub fn init() {
     unsafe {
         // enable timer interrupt